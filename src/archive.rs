@@ -1,38 +1,212 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::{self},
     path::Path,
-    process::{self, Stdio, exit},
+    process::exit,
 };
 
 use anyhow::anyhow;
-use chrono::{Local, TimeZone};
+use chrono::{DateTime, Local, TimeZone};
 use lazy_static::lazy_static;
 use regex::Regex;
 
+use crate::archive_journal::ArchiveJournal;
 use crate::kbi_verification::collect_kbi_objects;
+use crate::repo_verification::is_repo_object;
+use crate::retention::RetentionPolicy;
 
 pub fn archive_backups(
     incr_repo: String,
     backups: String,
     archive_incr_repo: String,
     archive_backups: String,
-    dur: String,
+    policy: RetentionPolicy,
     dry_run: bool,
 ) {
-    // 1. list all backups, mark them as active
-    // 2. mark old backups (.kbi index files, .zip full backups) as inactive, move them to archive directory
-    // 3. list all files in incremental repo, mark them as inactive
-    // 4. mark all files referenced in active .kbi files as active
-    // 5. move all inactive files from incremental repo to archive directory
-    let dur = match duration_str::parse(dur) {
+    let journal_path = ArchiveJournal::path(&incr_repo);
+    match ArchiveJournal::load(&journal_path) {
+        Ok(Some(_)) if dry_run => {
+            tracing::error!("an interrupted archive is found, run with --resume first");
+            exit(1);
+        }
+        Ok(Some(_)) => resume_archive(
+            incr_repo.clone(),
+            Some(backups.clone()),
+            Some(archive_incr_repo.clone()),
+            Some(archive_backups.clone()),
+            false,
+        ),
+        Ok(None) => {}
+        Err(why) => {
+            tracing::error!("error reading archive journal: {}", why);
+            exit(1);
+        }
+    }
+
+    // move all inactive backups and objects to archive directory
+    let plan = plan_expiry(&incr_repo, &backups, &policy, dry_run);
+    if dry_run {
+        for name in plan.backups.iter().chain(plan.objects.iter()) {
+            tracing::info!("archived: {}", name);
+        }
+        return;
+    }
+    // a journal with relative paths cannot be resumed from another working directory
+    let canonical = |path: &str| match fs::canonicalize(path) {
         Ok(v) => v,
         Err(why) => {
-            tracing::error!("cannot parse duration string: {}", why);
+            tracing::error!("cannot resolve {}: {}", path, why);
+            exit(1);
+        }
+    };
+    let mut journal = ArchiveJournal {
+        backups_dir: canonical(&backups),
+        archive_backups_dir: canonical(&archive_backups),
+        incr_repo: canonical(&incr_repo),
+        archive_incr_repo: canonical(&archive_incr_repo),
+        backups: plan.backups,
+        objects: plan.objects,
+    };
+    if let Err(why) = journal.save(&journal_path) {
+        tracing::error!("error writing archive journal: {}", why);
+        exit(1);
+    }
+    let failed = journal.move_backups();
+    if failed > 0 {
+        // objects may still be used by backups which were not moved
+        tracing::error!(
+            "failed to move {} backup files, objects are not moved; run with --resume to retry or --rollback",
+            failed
+        );
+        exit(1);
+    }
+    // backups created meanwhile may use objects planned to move
+    if let Err(why) = journal.drop_objects_of_new_backups(&plan.active_backups) {
+        tracing::warn!("{}, objects are not moved", why);
+        journal.objects.clear();
+    }
+    if let Err(why) = journal.save(&journal_path) {
+        tracing::error!("error writing archive journal: {}", why);
+        exit(1);
+    }
+    let failed = journal.move_objects();
+    if failed > 0 {
+        tracing::error!(
+            "failed to move {} incremental object files; run with --resume to retry or --rollback",
+            failed
+        );
+        exit(1);
+    }
+    if let Err(why) = fs::remove_file(&journal_path) {
+        tracing::error!("error removing archive journal: {}", why);
+        exit(1);
+    }
+}
+
+/// Complete or roll back an interrupted archive according to its journal.
+/// Directories which are given must be the ones in the journal.
+pub fn resume_archive(
+    incr_repo: String,
+    backups: Option<String>,
+    archive_incr_repo: Option<String>,
+    archive_backups: Option<String>,
+    rollback: bool,
+) {
+    let journal_path = ArchiveJournal::path(&incr_repo);
+    let mut journal = match ArchiveJournal::load(&journal_path) {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            tracing::info!("no interrupted archive found");
+            return;
+        }
+        Err(why) => {
+            tracing::error!("error reading archive journal: {}", why);
             exit(1);
         }
     };
-    let mut all_backups: HashMap<String, bool> = match fs::read_dir(&backups) {
+    if let Err(why) = journal.check_dirs(
+        &incr_repo,
+        backups.as_deref(),
+        archive_incr_repo.as_deref(),
+        archive_backups.as_deref(),
+    ) {
+        tracing::error!("{}", why);
+        exit(1);
+    }
+    let failed = if rollback {
+        tracing::info!("rolling back interrupted archive");
+        journal.rollback()
+    } else {
+        tracing::info!("completing interrupted archive");
+        match journal.move_backups() {
+            0 => {
+                // backups created after the journal was written may use objects planned to move
+                if let Err(why) = journal
+                    .drop_objects_of_new_backups(&[])
+                    .and_then(|()| journal.save(&journal_path))
+                {
+                    tracing::error!("{}, objects are not moved", why);
+                    exit(1);
+                }
+                journal.move_objects()
+            }
+            n => n,
+        }
+    };
+    if failed > 0 {
+        tracing::error!("failed to move {} files, the journal is kept", failed);
+        exit(1);
+    }
+    if let Err(why) = fs::remove_file(&journal_path) {
+        tracing::error!("error removing archive journal: {}", why);
+        exit(1);
+    }
+}
+
+/// Objects used by .kbi files in `backups` which are not in `known`.
+pub fn new_backup_objects(
+    incr_repo: &str,
+    backups: &str,
+    known: &HashSet<&String>,
+) -> anyhow::Result<HashSet<String>> {
+    let mut used = HashSet::new();
+    for (_, file_name) in list_kbi_files(backups)? {
+        if known.contains(&file_name) {
+            continue;
+        }
+        let p = Path::new(backups).join(&file_name);
+        let objects = collect_kbi_objects(p.to_string_lossy().into_owned(), incr_repo.to_string())
+            .map_err(|why| anyhow!("error decoding new kbi file {}: {}", file_name, why))?;
+        used.extend(objects.into_iter().map(|(_, obj)| obj));
+    }
+    Ok(used)
+}
+
+/// Backups expired by a retention policy, and objects not referenced by any remaining backup
+pub struct ExpiryPlan {
+    pub backups: Vec<String>,
+    pub objects: Vec<String>,
+    /// Backups kept, including files with unrecognized names
+    pub active_backups: Vec<String>,
+}
+
+/// Find backups not kept by the policy and objects only they reference.
+/// With `print_table`, print which rule kept each backup.
+pub fn plan_expiry(
+    incr_repo: &str,
+    backups: &str,
+    policy: &RetentionPolicy,
+    print_table: bool,
+) -> ExpiryPlan {
+    // 1. list all backups, mark them as active
+    // 2. mark backups (.kbi index files, .zip full backups) not kept by the retention policy as inactive
+    // 3. list all files in incremental repo, mark them as inactive
+    // 4. mark all files referenced in active .kbi files as active
+    if policy.is_empty() {
+        tracing::error!("no retention rule given, refusing to expire all backups");
+        exit(1);
+    }
+    let mut all_backups: HashMap<String, bool> = match fs::read_dir(backups) {
         Ok(v) => v
             .map(|r| match r {
                 Ok(entry) => entry,
@@ -61,7 +235,7 @@ pub fn archive_backups(
             exit(1);
         }
     };
-    let mut incr_objects: HashMap<String, bool> = match fs::read_dir(&incr_repo) {
+    let mut incr_objects: HashMap<String, bool> = match fs::read_dir(incr_repo) {
         Ok(v) => v
             .map(|r| match r {
                 Ok(entry) => entry,
@@ -84,35 +258,43 @@ pub fn archive_backups(
                     exit(1);
                 }
             })
+            .filter(|(file_name, _)| is_repo_object(file_name))
             .collect(),
         Err(why) => {
             tracing::error!("failed to list incr files: {}", why);
             exit(1);
         }
     };
-    let t0 = Local::now() - dur; // items where create_time < t0 is considered inactive
+    let kept = match apply_retention_policy(policy, all_backups.keys(), print_table) {
+        Ok(v) => v,
+        Err(why) => {
+            tracing::error!("cannot apply retention policy: {}", why);
+            exit(1);
+        }
+    };
     for (filename, v) in all_backups.iter_mut() {
-        let t = match parse_archive_time_from_filename(&filename) {
-            Ok(v) => v,
-            Err(why) => {
-                tracing::error!("{}", why);
-                continue;
-            }
-        };
-        *v = t >= t0;
+        // files with unrecognized names are never archived
+        *v = kept.contains_key(filename) || parse_archive_time_from_filename(filename).is_err();
         if *v {
             tracing::debug!("active: {}", &filename);
         } else {
             tracing::debug!("inactive: {}", &filename);
         }
         if *v && filename.ends_with(".kbi") {
-            let p = Path::new(&backups)
+            let p = Path::new(backups)
                 .join(filename)
                 .into_os_string()
                 .into_string()
                 .unwrap();
             // active backup, mark all objects as active
-            for (_, obj_filename) in collect_kbi_objects(p, incr_repo.clone()) {
+            let objects = match collect_kbi_objects(p, incr_repo.to_string()) {
+                Ok(v) => v,
+                Err(why) => {
+                    tracing::error!("error decoding kbi file {}: {}", &filename, why);
+                    exit(1);
+                }
+            };
+            for (_, obj_filename) in objects {
                 if !incr_objects.contains_key(&obj_filename) {
                     tracing::error!(
                         "missing file used in backup: {}, used in {}",
@@ -126,80 +308,83 @@ pub fn archive_backups(
         }
     }
 
-    all_backups
+    let active_backups = all_backups
         .iter()
-        .filter(|(_, active)| !**active)
-        .for_each(|(backup, _)| {
-            if dry_run {
-                tracing::info!("archived: {}", backup);
-                return;
-            }
-            if let Err(why) = process::Command::new("mv")
-                .stdout(Stdio::inherit())
-                .stderr(Stdio::inherit())
-                .arg(
-                    Path::new(&backups)
-                        .join(backup)
-                        .into_os_string()
-                        .into_string()
-                        .unwrap(),
-                )
-                .arg(
-                    Path::new(&archive_backups)
-                        .join(backup)
-                        .into_os_string()
-                        .into_string()
-                        .unwrap(),
-                )
-                .output()
-            {
-                tracing::error!("error moving backup file {}: {}", backup, why);
-                exit(1);
-            } else {
-                tracing::info!("archived: {}", backup);
-            }
-        });
-    incr_objects
-        .iter()
-        .filter(|(_, active)| !**active)
-        .for_each(|(backup, _)| {
-            if dry_run {
-                tracing::info!("archived: {}", backup);
-                return;
-            }
-            if let Err(why) = process::Command::new("mv")
-                .stdout(Stdio::inherit())
-                .stderr(Stdio::inherit())
-                .arg(
-                    Path::new(&incr_repo)
-                        .join(backup)
-                        .into_os_string()
-                        .into_string()
-                        .unwrap(),
-                )
-                .arg(
-                    Path::new(&archive_incr_repo)
-                        .join(backup)
-                        .into_os_string()
-                        .into_string()
-                        .unwrap(),
-                )
-                .output()
-            {
-                tracing::error!("error moving incremental object file {}: {}", backup, why);
-                exit(1);
-            } else {
-                tracing::info!("archived: {}", backup);
+        .filter(|(_, active)| **active)
+        .map(|(name, _)| name.clone())
+        .collect();
+    let mut plan = ExpiryPlan {
+        backups: inactive(all_backups),
+        objects: inactive(incr_objects),
+        active_backups,
+    };
+    plan.backups.sort();
+    plan.objects.sort();
+    plan
+}
+
+fn inactive(items: HashMap<String, bool>) -> Vec<String> {
+    items
+        .into_iter()
+        .filter(|(_, active)| !*active)
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// Apply the policy to .kbi and .zip backups separately, returns the rules keeping each backup.
+/// With `print_table`, print a table of all backups.
+fn apply_retention_policy<'a, I: Iterator<Item = &'a String>>(
+    policy: &RetentionPolicy,
+    file_names: I,
+    print_table: bool,
+) -> anyhow::Result<HashMap<String, Vec<&'static str>>> {
+    let mut kbi_backups = Vec::new();
+    let mut zip_backups = Vec::new();
+    for file_name in file_names {
+        let t = match parse_archive_time_from_filename(file_name) {
+            Ok(v) => v,
+            Err(why) => {
+                tracing::error!("{}", why);
+                continue;
             }
-        });
+        };
+        if file_name.ends_with(".kbi") {
+            kbi_backups.push((t, file_name.clone()));
+        } else {
+            zip_backups.push((t, file_name.clone()));
+        }
+    }
+    let now = Local::now();
+    let mut kept = policy.apply(&kbi_backups, now)?;
+    kept.extend(policy.apply(&zip_backups, now)?);
+    if print_table {
+        let mut all: Vec<_> = kbi_backups.iter().chain(zip_backups.iter()).collect();
+        all.sort();
+        for (t, file_name) in all {
+            let rules = match kept.get(file_name) {
+                Some(rules) => rules.join(","),
+                None => "expired".to_string(),
+            };
+            println!(
+                "{} {:<24} {}",
+                t.format("%Y-%m-%d %H:%M:%S"),
+                rules,
+                file_name
+            );
+        }
+    }
+    Ok(kept)
 }
 
 lazy_static! {
     static ref filename_re: Regex =
-        Regex::new(r"^(kbackup|incremental)-(\d{4}-\d\d-\d\d_\d\d-\d\d-\d\d)_\S+\.(kbi|zip)$").unwrap();
+        Regex::new(r"^(kbackup|incremental)-(\d{4}-\d\d-\d\d_\d\d-\d\d-\d\d)_(\S+)\.(kbi|zip)$")
+            .unwrap();
 }
 
-fn parse_archive_time_from_filename(file_name: &str) -> anyhow::Result<chrono::DateTime<Local>> {
+pub fn parse_archive_time_from_filename(
+    file_name: &str,
+) -> anyhow::Result<chrono::DateTime<Local>> {
     let m = match filename_re.captures(file_name) {
         Some(m) => m.get(2).expect("invalid regex for filename"),
         None => {
@@ -217,3 +402,59 @@ fn parse_archive_time_from_filename(file_name: &str) -> anyhow::Result<chrono::D
         Err(why) => Err(anyhow!("error parsing date from filename: {}", why)),
     }
 }
+
+/// The backup name part in the file name, e.g. `foo` in `kbackup-2024-03-05_12-00-00_foo.zip`
+pub fn parse_backup_name_from_filename(file_name: &str) -> anyhow::Result<&str> {
+    match filename_re.captures(file_name) {
+        Some(m) => Ok(m.get(3).expect("invalid regex for filename").as_str()),
+        None => Err(anyhow!(
+            "unrecognized pattern of backup filename: {}",
+            file_name
+        )),
+    }
+}
+
+/// Parse a time like `2024-03-05` or `2024-03-05_12-00-00`.
+/// A date covers the whole day, so `end` selects its last second.
+pub fn parse_time_bound(s: &str, end: bool) -> anyhow::Result<DateTime<Local>> {
+    let t = match chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d_%H-%M-%S") {
+        Ok(v) => v,
+        Err(_) => {
+            let date = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .map_err(|_| anyhow!("neither a backup file name nor a time: {}", s))?;
+            let time = if end {
+                chrono::NaiveTime::from_hms_opt(23, 59, 59)
+            } else {
+                chrono::NaiveTime::from_hms_opt(0, 0, 0)
+            };
+            date.and_time(time.expect("invalid time"))
+        }
+    };
+    Local
+        .from_local_datetime(&t)
+        .single()
+        .ok_or_else(|| anyhow!("ambiguous local time: {}", s))
+}
+
+/// List `.kbi` files in the backups folder, sorted by the time in their file names.
+/// Files not following the naming convention are sorted to the front with no time.
+pub fn list_kbi_files(backups: &str) -> anyhow::Result<Vec<(Option<DateTime<Local>>, String)>> {
+    let mut result = Vec::new();
+    for entry in fs::read_dir(backups)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let file_name = match entry.file_name().into_string() {
+            Ok(v) => v,
+            Err(s) => return Err(anyhow!("cannot decode OsString {:?}", s)),
+        };
+        if !file_name.ends_with(".kbi") {
+            continue;
+        }
+        let t = parse_archive_time_from_filename(&file_name).ok();
+        result.push((t, file_name));
+    }
+    result.sort();
+    Ok(result)
+}
@@ -0,0 +1,150 @@
+use crate::archive::new_backup_objects;
+use crate::move_file::move_file;
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// File name of the journal inside the incremental repo
+pub const JOURNAL_FILE_NAME: &str = ".kbackup-utils-archive.journal";
+
+/// Planned moves of an archive run, written before any file is moved.
+/// Backups are moved before objects, so no backup left in place references a moved object.
+#[derive(Serialize, Deserialize)]
+pub struct ArchiveJournal {
+    pub backups_dir: PathBuf,
+    pub archive_backups_dir: PathBuf,
+    pub incr_repo: PathBuf,
+    pub archive_incr_repo: PathBuf,
+    pub backups: Vec<String>,
+    pub objects: Vec<String>,
+}
+
+impl ArchiveJournal {
+    pub fn path(incr_repo: &str) -> PathBuf {
+        Path::new(incr_repo).join(JOURNAL_FILE_NAME)
+    }
+
+    /// Load the journal of an interrupted run, if any.
+    pub fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        match File::open(path) {
+            Ok(f) => Ok(Some(serde_json::from_reader(BufReader::new(f))?)),
+            Err(why) if why.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(why) => Err(why.into()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut w, self)?;
+        w.flush()?;
+        w.into_inner()?.sync_all()?;
+        fs::rename(&tmp, path)?;
+        if let Some(dir) = path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Fail if an interrupted archive left its journal in the repo, as its moves are incomplete.
+    pub fn ensure_none_pending(incr_repo: &str) -> anyhow::Result<()> {
+        if Self::path(incr_repo).try_exists()? {
+            return Err(anyhow!(
+                "an interrupted archive is found, run archive with --resume or --rollback first"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fail if a directory given on the command line is not the one recorded in the journal.
+    pub fn check_dirs(
+        &self,
+        incr_repo: &str,
+        backups: Option<&str>,
+        archive_incr_repo: Option<&str>,
+        archive_backups: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let dirs = [
+            (Some(incr_repo), &self.incr_repo),
+            (backups, &self.backups_dir),
+            (archive_incr_repo, &self.archive_incr_repo),
+            (archive_backups, &self.archive_backups_dir),
+        ];
+        for (given, recorded) in dirs {
+            let Some(given) = given else { continue };
+            let canonical = fs::canonicalize(given)
+                .map_err(|why| anyhow!("cannot resolve {}: {}", given, why))?;
+            if canonical != *recorded {
+                return Err(anyhow!(
+                    "{} does not match {} in the journal of the interrupted archive",
+                    given,
+                    recorded.display()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop objects used by any backup which is neither planned to move nor in `scanned`,
+    /// whose objects are known to be excluded already.
+    pub fn drop_objects_of_new_backups(&mut self, scanned: &[String]) -> anyhow::Result<()> {
+        let known: HashSet<&String> = scanned.iter().chain(self.backups.iter()).collect();
+        let used = new_backup_objects(
+            &self.incr_repo.to_string_lossy(),
+            &self.backups_dir.to_string_lossy(),
+            &known,
+        )?;
+        for obj in self.objects.iter().filter(|obj| used.contains(*obj)) {
+            if !self.incr_repo.join(obj).exists() {
+                tracing::warn!("object used by a new backup is already archived: {}", obj);
+            }
+        }
+        self.objects.retain(|obj| !used.contains(obj));
+        Ok(())
+    }
+
+    /// Move backups to the archive, returns the number of failures.
+    pub fn move_backups(&self) -> usize {
+        move_all(&self.backups, &self.backups_dir, &self.archive_backups_dir)
+    }
+
+    /// Move objects to the archive, returns the number of failures.
+    pub fn move_objects(&self) -> usize {
+        move_all(&self.objects, &self.incr_repo, &self.archive_incr_repo)
+    }
+
+    /// Move everything back in reverse order, returns the number of failures.
+    pub fn rollback(&self) -> usize {
+        let failed = move_all(&self.objects, &self.archive_incr_repo, &self.incr_repo);
+        if failed > 0 {
+            // backups moved back must not reference objects still in the archive
+            return failed;
+        }
+        move_all(&self.backups, &self.archive_backups_dir, &self.backups_dir)
+    }
+}
+
+/// Move files from `src` to `dst`, files already in `dst` were moved by an earlier run.
+fn move_all(names: &[String], src: &Path, dst: &Path) -> usize {
+    let mut failed = 0;
+    for name in names {
+        let (from, to) = (src.join(name), dst.join(name));
+        if !from.exists() && to.exists() {
+            tracing::debug!("already moved: {}", name);
+            continue;
+        }
+        match move_file(&from, &to) {
+            Ok(()) => tracing::info!("moved: {} to {}", name, dst.display()),
+            Err(why) => {
+                tracing::error!("error moving {}: {}", from.display(), why);
+                failed += 1;
+            }
+        }
+    }
+    failed
+}
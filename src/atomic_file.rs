@@ -0,0 +1,58 @@
+use crate::hash_algorithm::HashAlgorithm;
+use crate::repo_verification::HashingWriter;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Hidden file name, unique among concurrent runs and threads
+fn tmp_name() -> String {
+    format!(
+        ".kbackup-utils-{}-{}.tmp",
+        process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Copy `r` into a new temp file in `dir` while hashing it, and fsync the file.
+/// `dst` gets the temp file and the hash, and returns where in `dir` to rename it to, or `None` to drop it.
+/// After the rename `dir` is fsynced. The temp file never outlives the call.
+/// Returns the hash and the size of the data.
+pub fn write_atomic<R: Read>(
+    mut r: R,
+    dir: &Path,
+    alg: HashAlgorithm,
+    dst: impl FnOnce(&Path, &str) -> anyhow::Result<Option<PathBuf>>,
+) -> anyhow::Result<(String, u64)> {
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let tmp = dir.join(tmp_name());
+    let file = OpenOptions::new().write(true).create_new(true).open(&tmp)?;
+    let mut renamed = false;
+    let result = (|| {
+        let mut writer = HashingWriter::new(file, alg);
+        let size = io::copy(&mut r, &mut writer)?;
+        writer.flush()?;
+        let (file, hash) = writer.finish();
+        file.sync_all()?;
+        drop(file);
+        if let Some(dst) = dst(&tmp, &hash)? {
+            fs::rename(&tmp, dst)?;
+            renamed = true;
+            File::open(dir)?.sync_all()?;
+        }
+        Ok((hash, size))
+    })();
+    if !renamed && let Err(why) = fs::remove_file(&tmp) {
+        tracing::warn!("error removing {}: {}", tmp.display(), why);
+    }
+    result
+}
@@ -0,0 +1,202 @@
+use crate::archive::list_kbi_files;
+use crate::atomic_file::write_atomic;
+use crate::hash_algorithm::HashAlgorithm;
+use crate::java_objects::{
+    JavaHashMap, KbiClassDescs, ObjectCollection2, ObjectElement, SavedIncBackupV1,
+    SingleHashIdentifier, ZonedDateTime,
+};
+use crate::kbi_verification::write_kbi;
+use crate::repo_verification::hash_file;
+use anyhow::anyhow;
+use chrono::Local;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+#[derive(Default)]
+pub struct BackupStats {
+    pub total_size_bytes: i64,
+    pub increased_size_bytes: i64,
+    pub files_added: i32,
+    pub total_files: i32,
+}
+
+pub fn create_backup(
+    world: String,
+    incr_repo: String,
+    backups: String,
+    name: String,
+    template: Option<String>,
+) {
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        tracing::error!("backup name must be non-empty and contain no whitespace");
+        exit(1);
+    }
+    let descs = match load_class_descs(&backups, template) {
+        Ok(v) => v,
+        Err(why) => {
+            tracing::error!("cannot get class descriptors of .kbi files: {}", why);
+            exit(1);
+        }
+    };
+    let world = PathBuf::from(world);
+    let root_name = match world
+        .canonicalize()
+        .ok()
+        .and_then(|p| p.file_name().map(|s| s.to_string_lossy().into_owned()))
+    {
+        Some(v) => v,
+        None => {
+            tracing::error!("invalid world directory: {}", world.display());
+            exit(1);
+        }
+    };
+    let now = Local::now();
+    let kbi_path = Path::new(&backups).join(format!(
+        "incremental-{}_{}.kbi",
+        now.format("%Y-%m-%d_%H-%M-%S"),
+        name
+    ));
+    if kbi_path.exists() {
+        tracing::error!("backup file already exists: {}", kbi_path.display());
+        exit(1);
+    }
+
+    let mut stats = BackupStats::default();
+    let object_collection2 =
+        match store_collection(&world, root_name, Path::new(&incr_repo), &mut stats) {
+            Ok(v) => v,
+            Err(why) => {
+                tracing::error!("error backing up {}: {}", world.display(), why);
+                exit(1);
+            }
+        };
+    let backup_info = SavedIncBackupV1 {
+        object_collection2,
+        backup_name: name,
+        backup_time: ZonedDateTime(now.fixed_offset(), None),
+        total_size_bytes: stats.total_size_bytes,
+        increased_size_bytes: stats.increased_size_bytes,
+        files_added: stats.files_added,
+        total_files: stats.total_files,
+    };
+    if let Err(why) = write_kbi(&kbi_path, &backup_info, &descs) {
+        tracing::error!("error writing {}: {}", kbi_path.display(), why);
+        exit(1);
+    }
+    tracing::info!(
+        "created backup {}: {} files, {} bytes, {} new objects, {} bytes increased",
+        kbi_path.display(),
+        stats.total_files,
+        stats.total_size_bytes,
+        stats.files_added,
+        stats.increased_size_bytes
+    );
+}
+
+/// Class descriptors are copied from the template, or the latest .kbi file in the backups folder.
+pub fn load_class_descs(backups: &str, template: Option<String>) -> anyhow::Result<KbiClassDescs> {
+    let template = match template {
+        Some(v) => PathBuf::from(v),
+        None => match list_kbi_files(backups)?.pop() {
+            Some((_, file_name)) => Path::new(backups).join(file_name),
+            None => {
+                return Err(anyhow!(
+                    "no .kbi file in {} to use as template, specify one with --template",
+                    backups
+                ));
+            }
+        },
+    };
+    tracing::debug!("using class descriptors in {}", template.display());
+    KbiClassDescs::from_kbi(File::open(template)?)
+}
+
+fn store_collection(
+    dir: &Path,
+    name: String,
+    incr_repo: &Path,
+    stats: &mut BackupStats,
+) -> anyhow::Result<ObjectCollection2> {
+    let mut elements = HashMap::new();
+    let mut sub_collections = HashMap::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = match entry.file_name().into_string() {
+            Ok(v) => v,
+            Err(s) => return Err(anyhow!("cannot decode OsString {:?}", s)),
+        };
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            let coll = store_collection(&entry.path(), file_name.clone(), incr_repo, stats)?;
+            sub_collections.insert(file_name, coll);
+        } else if file_type.is_file() {
+            let identifier = store_object(&entry.path(), incr_repo, stats)?;
+            elements.insert(
+                file_name.clone(),
+                ObjectElement {
+                    name: file_name,
+                    identifier,
+                },
+            );
+        } else {
+            tracing::warn!("skipped non-regular file: {}", entry.path().display());
+        }
+    }
+    Ok(ObjectCollection2 {
+        name,
+        elements: JavaHashMap::from(elements),
+        sub_collections: JavaHashMap::from(sub_collections),
+    })
+}
+
+/// Store the file into the repo if the object does not exist yet.
+pub fn store_object(
+    path: &Path,
+    incr_repo: &Path,
+    stats: &mut BackupStats,
+) -> anyhow::Result<SingleHashIdentifier> {
+    let alg = HashAlgorithm::DEFAULT;
+    let hash = hash_file(alg, path.to_path_buf())?;
+    let obj_path = incr_repo.join(format!("{}-{}", alg.type_name(), hash));
+    if !obj_path.is_file() {
+        // the file may change after hashing, so the object is named after what is actually copied
+        return store_reader(File::open(path)?, incr_repo, stats);
+    }
+    stats.total_files += 1;
+    stats.total_size_bytes += fs::metadata(&obj_path)?.len() as i64;
+    Ok(SingleHashIdentifier {
+        typ: alg.type_name().to_string(),
+        hash: hex::decode(&hash)?,
+    })
+}
+
+/// Store all data in the reader into the repo if the object does not exist yet.
+pub fn store_reader<R: Read>(
+    r: R,
+    incr_repo: &Path,
+    stats: &mut BackupStats,
+) -> anyhow::Result<SingleHashIdentifier> {
+    let alg = HashAlgorithm::DEFAULT;
+    let mut added = false;
+    let (hash, size) = write_atomic(r, incr_repo, alg, |_, hash| {
+        let obj_path = incr_repo.join(format!("{}-{}", alg.type_name(), hash));
+        added = !obj_path.is_file();
+        Ok(added.then_some(obj_path))
+    })?;
+    let size = size as i64;
+    if added {
+        stats.files_added += 1;
+        stats.increased_size_bytes += size;
+        tracing::debug!("stored: {}-{}", alg.type_name(), hash);
+    }
+    stats.total_files += 1;
+    stats.total_size_bytes += size;
+    Ok(SingleHashIdentifier {
+        typ: alg.type_name().to_string(),
+        hash: hex::decode(&hash)?,
+    })
+}
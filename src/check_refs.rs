@@ -0,0 +1,122 @@
+use crate::archive::list_kbi_files;
+use crate::kbi_verification::{list_files, load_kbi};
+use crate::repo_verification::{EXIT_CORRUPTED, EXIT_IO_ERROR, list_repo_objects};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+/// World files that are required to load the world at all
+const REQUIRED_FILES: [&str; 1] = ["level.dat"];
+/// Exit code when some backups are unrestorable
+const EXIT_UNRESTORABLE: i32 = 4;
+
+/// Report objects referenced by .kbi files but missing in the incremental repo.
+/// Exits with `EXIT_CORRUPTED` if some backups are partially damaged, `EXIT_UNRESTORABLE` if some
+/// backups are unrestorable, and `EXIT_IO_ERROR` if the backups or objects cannot be listed.
+pub fn check_refs(incr_repo: String, backups: String) {
+    let kbi_files = match list_kbi_files(&backups) {
+        Ok(v) => v,
+        Err(why) => {
+            tracing::error!("failed to list backup files: {}", why);
+            exit(EXIT_IO_ERROR);
+        }
+    };
+    let objects: HashSet<String> = match list_repo_objects(&incr_repo) {
+        Ok(v) => v.into_iter().collect(),
+        Err(why) => {
+            tracing::error!("failed to list incr files: {}", why);
+            exit(EXIT_IO_ERROR);
+        }
+    };
+
+    let mut ok = 0usize;
+    let mut damaged = 0usize;
+    let mut unrestorable = 0usize;
+    // path -> (object, backups using it)
+    let mut missing_by_path: BTreeMap<PathBuf, BTreeMap<String, Vec<&str>>> = BTreeMap::new();
+    for (_, file_name) in kbi_files.iter() {
+        let backup_info = match load_kbi(&Path::new(&backups).join(file_name).to_string_lossy()) {
+            Ok(v) => v,
+            Err(why) => {
+                println!("{}: unrestorable, cannot decode: {}", file_name, why);
+                unrestorable += 1;
+                continue;
+            }
+        };
+        let root = &backup_info.object_collection2;
+        let files = list_files(root);
+        let missing: Vec<_> = files
+            .iter()
+            .filter(|(_, elem)| !objects.contains(&elem.identifier.to_string()))
+            .collect();
+        if missing.is_empty() {
+            ok += 1;
+            continue;
+        }
+        let required_missing = missing.iter().any(|(path, _)| {
+            REQUIRED_FILES
+                .iter()
+                .any(|f| **path == Path::new(&root.name).join(f))
+        });
+        if required_missing || missing.len() == files.len() {
+            println!(
+                "{}: unrestorable, {} of {} objects missing",
+                file_name,
+                missing.len(),
+                files.len()
+            );
+            unrestorable += 1;
+        } else {
+            println!(
+                "{}: partially damaged, {} of {} objects missing",
+                file_name,
+                missing.len(),
+                files.len()
+            );
+            damaged += 1;
+        }
+        for (path, elem) in missing {
+            let obj = elem.identifier.to_string();
+            println!("    {} {}", obj, path.display());
+            missing_by_path
+                .entry(path.clone())
+                .or_default()
+                .entry(obj)
+                .or_default()
+                .push(file_name);
+        }
+    }
+
+    if !missing_by_path.is_empty() {
+        println!("missing objects by path:");
+        for (path, objs) in missing_by_path.iter() {
+            for (obj, backups) in objs {
+                println!(
+                    "    {} {}, used in {} backups: {}",
+                    path.display(),
+                    obj,
+                    backups.len(),
+                    backups.join(", ")
+                );
+            }
+        }
+    }
+    let missing_objects: HashSet<&String> = missing_by_path
+        .values()
+        .flat_map(|objs| objs.keys())
+        .collect();
+    println!(
+        "checked {} backups: {} ok, {} partially damaged, {} unrestorable, {} missing objects",
+        kbi_files.len(),
+        ok,
+        damaged,
+        unrestorable,
+        missing_objects.len()
+    );
+    if unrestorable > 0 {
+        exit(EXIT_UNRESTORABLE);
+    }
+    if damaged > 0 {
+        exit(EXIT_CORRUPTED);
+    }
+}
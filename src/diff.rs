@@ -0,0 +1,101 @@
+use crate::kbi_verification::{list_files, load_kbi};
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::exit;
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Change {
+    Added,
+    Removed,
+    Modified,
+}
+
+#[derive(Serialize)]
+struct DiffEntry {
+    path: String,
+    change: Change,
+    old_object: Option<String>,
+    new_object: Option<String>,
+    /// `None` if any of the objects cannot be found in the repo
+    size_delta: Option<i64>,
+}
+
+pub fn diff_kbi(old_kbi: String, new_kbi: String, repo_path: Option<String>, json: bool) {
+    let load = |path: &String| match load_kbi(path) {
+        Ok(v) => v,
+        Err(why) => {
+            tracing::error!("error decoding kbi file {}: {}", path, why);
+            exit(1);
+        }
+    };
+    let old_backup = load(&old_kbi);
+    let new_backup = load(&new_kbi);
+    let old_files = list_files(&old_backup.object_collection2);
+    let new_files = list_files(&new_backup.object_collection2);
+    let object_size = |id: &Option<String>| -> Option<i64> {
+        match id {
+            Some(id) => fs::metadata(Path::new(repo_path.as_ref()?).join(id))
+                .ok()
+                .map(|m| m.len() as i64),
+            None => Some(0),
+        }
+    };
+
+    let mut diff = Vec::new();
+    let paths = old_files
+        .keys()
+        .chain(new_files.keys().filter(|p| !old_files.contains_key(*p)));
+    for path in paths {
+        let old_object = old_files.get(path).map(|e| e.identifier.to_string());
+        let new_object = new_files.get(path).map(|e| e.identifier.to_string());
+        let change = match (&old_object, &new_object) {
+            (Some(a), Some(b)) if a == b => continue,
+            (Some(_), Some(_)) => Change::Modified,
+            (Some(_), None) => Change::Removed,
+            (None, _) => Change::Added,
+        };
+        let size_delta = match (object_size(&old_object), object_size(&new_object)) {
+            (Some(a), Some(b)) => Some(b - a),
+            _ => None,
+        };
+        diff.push(DiffEntry {
+            path: path.to_string_lossy().into_owned(),
+            change,
+            old_object,
+            new_object,
+            size_delta,
+        });
+    }
+    diff.sort_by(|a, b| a.path.cmp(&b.path));
+
+    if json {
+        if let Err(why) = serde_json::to_writer(io::stdout(), &diff)
+            && !why.is_io()
+        {
+            tracing::error!("error encoding JSON: {}", why);
+        }
+        return;
+    }
+    for entry in diff.iter() {
+        let c = match entry.change {
+            Change::Added => 'A',
+            Change::Removed => 'D',
+            Change::Modified => 'M',
+        };
+        match entry.size_delta {
+            Some(d) => println!("{} {} ({:+})", c, entry.path, d),
+            None => println!("{} {}", c, entry.path),
+        }
+    }
+    let count = |c| diff.iter().filter(|e| e.change == c).count();
+    println!(
+        "{} added, {} removed, {} modified, total size delta: {:+}",
+        count(Change::Added),
+        count(Change::Removed),
+        count(Change::Modified),
+        new_backup.total_size_bytes - old_backup.total_size_bytes
+    );
+}
@@ -12,9 +12,9 @@ pub fn dump_kbi(path: String, pretty: bool) {
     } else {
         serde_json::to_writer
     };
-    if let Err(why) = f(io::stdout(), &backup_info) {
-        if !why.is_io() {
-            tracing::error!("error encoding JSON: {}", why);
-        }
+    if let Err(why) = f(io::stdout(), &backup_info)
+        && !why.is_io()
+    {
+        tracing::error!("error encoding JSON: {}", why);
     }
 }
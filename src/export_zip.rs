@@ -0,0 +1,127 @@
+use crate::hash_algorithm::HashAlgorithm;
+use crate::java_objects::{ObjectCollection2, ObjectElement};
+use crate::kbi_verification::{load_kbi, traverse_collections};
+use crate::repo_verification::HashingWriter;
+use anyhow::anyhow;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::{fs, io};
+use zip::write::{SimpleFileOptions, StreamWriter};
+use zip::{CompressionMethod, ZipWriter};
+
+/// Write files in a .kbi backup as a full backup .zip file, `-` for stdout.
+pub fn export_zip(kbi_path: String, repo_path: String, output: String) {
+    let backup_info = match load_kbi(&kbi_path) {
+        Ok(v) => v,
+        Err(why) => {
+            tracing::error!("error decoding kbi file {}: {}", &kbi_path, why);
+            exit(1);
+        }
+    };
+    // resolve the hash algorithm of every file before creating the zip
+    let dirs = match list_dirs(&backup_info.object_collection2) {
+        Ok(v) => v,
+        Err(why) => {
+            tracing::error!("error exporting zip: {}", why);
+            exit(1);
+        }
+    };
+    let out: Box<dyn Write> = if output == "-" {
+        Box::new(io::stdout().lock())
+    } else {
+        match File::create(&output) {
+            Ok(f) => Box::new(f),
+            Err(why) => {
+                tracing::error!("error creating {}: {}", &output, why);
+                exit(1);
+            }
+        }
+    };
+    let mtime =
+        zip::DateTime::try_from(backup_info.backup_time.0.naive_local()).unwrap_or_default();
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .last_modified_time(mtime);
+    let result = write_zip(
+        ZipWriter::new_stream(BufWriter::new(out)),
+        dirs,
+        Path::new(&repo_path),
+        options,
+    );
+    match result {
+        Ok(n) => tracing::info!("exported {} files", n),
+        Err(why) => {
+            tracing::error!("error exporting zip: {}", why);
+            if output != "-"
+                && let Err(why) = fs::remove_file(&output)
+            {
+                tracing::error!("error removing incomplete zip {}: {}", &output, why);
+            }
+            exit(1);
+        }
+    }
+}
+
+/// A directory in the backup with its files and the hash algorithm of each file
+type Dir<'a> = (PathBuf, Vec<(&'a ObjectElement, HashAlgorithm)>);
+
+/// Directories in the backup sorted by path, files in each directory sorted by name.
+fn list_dirs(root: &ObjectCollection2) -> anyhow::Result<Vec<Dir<'_>>> {
+    let mut collections: Vec<(PathBuf, &ObjectCollection2)> = Vec::new();
+    traverse_collections(Path::new(&root.name), root, &mut |dir, coll| {
+        collections.push((dir.to_path_buf(), coll));
+    });
+    collections.sort_by(|a, b| a.0.cmp(&b.0));
+    collections
+        .into_iter()
+        .map(|(dir, coll)| {
+            let mut elements = coll
+                .elements
+                .as_ref()
+                .values()
+                .map(|elem| Ok((elem, HashAlgorithm::from_type(&elem.identifier.typ)?)))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            elements.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+            Ok((dir, elements))
+        })
+        .collect()
+}
+
+fn write_zip<W: Write>(
+    mut zip: ZipWriter<StreamWriter<W>>,
+    dirs: Vec<Dir>,
+    repo: &Path,
+    options: SimpleFileOptions,
+) -> anyhow::Result<usize> {
+    let mut n = 0usize;
+    for (dir, elements) in dirs {
+        zip.add_directory(format!("{}/", dir.to_string_lossy()), options)?;
+        for (elem, alg) in elements {
+            let obj_name = elem.identifier.to_string();
+            let obj_path = repo.join(&obj_name);
+            let mut obj = File::open(&obj_path)
+                .map_err(|why| anyhow!("cannot open object {}: {}", obj_name, why))?;
+            let size = fs::metadata(&obj_path)?.len();
+            zip.start_file(
+                dir.join(&elem.name).to_string_lossy(),
+                options.large_file(size >= u32::MAX as u64),
+            )?;
+            let mut writer = HashingWriter::new(&mut zip, alg);
+            io::copy(&mut obj, &mut writer)?;
+            let actual_hash = writer.finish().1;
+            let expected_hash = hex::encode_upper(&elem.identifier.hash);
+            if actual_hash != expected_hash {
+                return Err(anyhow!(
+                    "object {} is corrupted, actual hash: {}",
+                    obj_name,
+                    actual_hash
+                ));
+            }
+            n += 1;
+        }
+    }
+    zip.finish()?.flush()?;
+    Ok(n)
+}
@@ -0,0 +1,106 @@
+use crate::java_objects::ObjectElement;
+use crate::kbi_verification::{Resolved, load_kbi, resolve, traverse_collections};
+use crate::restore::{check_names, restore_object};
+use globset::GlobBuilder;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+pub fn extract_kbi(kbi_path: String, repo_path: String, pattern: String, output: String) {
+    let backup_info = match load_kbi(&kbi_path) {
+        Ok(v) => v,
+        Err(why) => {
+            tracing::error!("error decoding kbi file {}: {}", &kbi_path, why);
+            exit(1);
+        }
+    };
+    let root = &backup_info.object_collection2;
+    if let Err(why) = check_names(root) {
+        tracing::error!("refusing to extract from {}: {}", &kbi_path, why);
+        exit(1);
+    }
+    let pattern = pattern.trim_matches('/');
+    let output = PathBuf::from(output);
+
+    // (destination, object) pairs to be extracted
+    let mut files: Vec<(PathBuf, &ObjectElement)> = Vec::new();
+    if is_glob(pattern) {
+        let glob = match GlobBuilder::new(pattern).literal_separator(true).build() {
+            Ok(v) => v.compile_matcher(),
+            Err(why) => {
+                tracing::error!("invalid glob pattern: {}", why);
+                exit(1);
+            }
+        };
+        // matched files are placed relative to the non-glob prefix of the pattern
+        let base: PathBuf = pattern.split('/').take_while(|c| !is_glob(c)).collect();
+        traverse_collections(Path::new(&root.name), root, &mut |dir, coll| {
+            for elem in coll.elements.as_ref().values() {
+                let path = dir.join(&elem.name);
+                if glob.is_match(&path) {
+                    let rel = path.strip_prefix(&base).unwrap_or(&path);
+                    files.push((output.join(rel), elem));
+                }
+            }
+        });
+    } else {
+        match resolve(root, pattern) {
+            Some(Resolved::File(elem)) => {
+                let dst = if output.is_dir() {
+                    output.join(&elem.name)
+                } else {
+                    output.clone()
+                };
+                files.push((dst, elem));
+            }
+            Some(Resolved::Collection(coll)) => {
+                traverse_collections(&output, coll, &mut |dir, coll| {
+                    for elem in coll.elements.as_ref().values() {
+                        files.push((dir.join(&elem.name), elem));
+                    }
+                });
+            }
+            None => {}
+        }
+    }
+    if files.is_empty() {
+        tracing::error!("no file in backup matches {}", pattern);
+        exit(1);
+    }
+
+    let repo = PathBuf::from(repo_path);
+    let mut failed = 0usize;
+    for (dst, elem) in files.iter() {
+        let obj_path = repo.join(elem.identifier.to_string());
+        if !obj_path.is_file() {
+            println!(
+                "missing object: {}, used by {}",
+                elem.identifier,
+                dst.display()
+            );
+            failed += 1;
+            continue;
+        }
+        if let Some(parent) = dst.parent()
+            && let Err(why) = fs::create_dir_all(parent)
+        {
+            tracing::error!("error creating directory {}: {}", parent.display(), why);
+            exit(1);
+        }
+        match restore_object(&obj_path, dst, &elem.identifier) {
+            Ok(()) => println!("extracted: {}", dst.display()),
+            Err(why) => {
+                tracing::error!("error extracting {}: {}", dst.display(), why);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        tracing::error!("{} of {} files failed to extract", failed, files.len());
+        exit(1);
+    }
+}
+
+fn is_glob(s: &str) -> bool {
+    s.contains(['*', '?', '[', '{'])
+}
@@ -0,0 +1,108 @@
+use crate::archive::list_kbi_files;
+use crate::archive_journal::ArchiveJournal;
+use crate::kbi_verification::collect_kbi_objects;
+use crate::move_file::move_file;
+use crate::repo_verification::list_repo_objects;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::process::exit;
+use std::time::SystemTime;
+
+/// Remove objects in the incremental repo not referenced by any .kbi file in the backups folder.
+/// Objects modified within `min_age` are kept, as a running backup writes its objects before its .kbi file.
+pub fn gc_objects(
+    incr_repo: String,
+    backups: String,
+    quarantine: Option<String>,
+    min_age: String,
+    dry_run: bool,
+) {
+    if let Err(why) = ArchiveJournal::ensure_none_pending(&incr_repo) {
+        tracing::error!("{}", why);
+        exit(1);
+    }
+    let not_after = match duration_str::parse(&min_age) {
+        Ok(dur) => SystemTime::now() - dur,
+        Err(why) => {
+            tracing::error!("cannot parse duration string: {}", why);
+            exit(1);
+        }
+    };
+    let kbi_files = match list_kbi_files(&backups) {
+        Ok(v) => v,
+        Err(why) => {
+            tracing::error!("failed to list backup files: {}", why);
+            exit(1);
+        }
+    };
+    let mut referenced = HashSet::new();
+    for (_, file_name) in kbi_files.iter() {
+        let p = Path::new(&backups).join(file_name);
+        match collect_kbi_objects(p.to_string_lossy().into_owned(), incr_repo.clone()) {
+            Ok(v) => referenced.extend(v.into_iter().map(|(_, obj)| obj)),
+            Err(why) => {
+                // an undecodable backup may reference anything, deleting objects is unsafe
+                tracing::error!("error decoding kbi file {}: {}, abort", file_name, why);
+                exit(1);
+            }
+        }
+    }
+    let orphans = match list_repo_objects(&incr_repo) {
+        Ok(v) => v
+            .into_iter()
+            .filter(|obj| !referenced.contains(obj))
+            .collect::<Vec<_>>(),
+        Err(why) => {
+            tracing::error!("failed to list incr files: {}", why);
+            exit(1);
+        }
+    };
+    // an object whose age is unknown may be new as well
+    let (mut orphans, young): (Vec<_>, Vec<_>) = orphans.into_iter().partition(|obj| {
+        fs::metadata(Path::new(&incr_repo).join(obj))
+            .and_then(|m| m.modified())
+            .is_ok_and(|t| t <= not_after)
+    });
+    if !young.is_empty() {
+        tracing::info!(
+            "skipped {} orphan objects newer than {}",
+            young.len(),
+            &min_age
+        );
+    }
+    orphans.sort();
+
+    let mut total_size = 0u64;
+    let mut failed = 0usize;
+    for obj in orphans.iter() {
+        let src = Path::new(&incr_repo).join(obj);
+        total_size += fs::metadata(&src).map(|m| m.len()).unwrap_or(0);
+        if dry_run {
+            println!("orphan: {}", obj);
+            continue;
+        }
+        let result = match &quarantine {
+            Some(dir) => move_file(&src, &Path::new(dir).join(obj)),
+            None => fs::remove_file(&src).map_err(Into::into),
+        };
+        match result {
+            Ok(()) if quarantine.is_some() => println!("quarantined: {}", obj),
+            Ok(()) => println!("deleted: {}", obj),
+            Err(why) => {
+                tracing::error!("error removing orphan object {}: {}", obj, why);
+                failed += 1;
+            }
+        }
+    }
+    tracing::info!(
+        "{} orphan objects in {} backups, {} bytes in total",
+        orphans.len(),
+        kbi_files.len(),
+        total_size
+    );
+    if failed > 0 {
+        tracing::error!("{} orphan objects failed to remove", failed);
+        exit(1);
+    }
+}
@@ -0,0 +1,172 @@
+use anyhow::anyhow;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::io;
+use std::io::Write;
+
+/// Hash algorithms of object identifiers, keyed by the identifier type string.
+/// Objects are stored in the repo as `<type>-<UPPER HEX HASH>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+/// Identifier type strings and their algorithms
+const REGISTRY: [(&str, HashAlgorithm); 4] = [
+    ("S1", HashAlgorithm::Sha1),
+    ("S2", HashAlgorithm::Sha256),
+    ("S5", HashAlgorithm::Sha512),
+    ("B3", HashAlgorithm::Blake3),
+];
+
+impl HashAlgorithm {
+    /// The algorithm used by KBackup for new objects
+    pub const DEFAULT: HashAlgorithm = HashAlgorithm::Sha256;
+
+    pub fn from_type(typ: &str) -> anyhow::Result<Self> {
+        REGISTRY
+            .iter()
+            .find(|(t, _)| *t == typ)
+            .map(|(_, alg)| *alg)
+            .ok_or_else(|| anyhow!("unknown hash identifier type: {}", typ))
+    }
+
+    pub fn type_name(self) -> &'static str {
+        REGISTRY
+            .iter()
+            .find(|(_, alg)| *alg == self)
+            .map(|(t, _)| *t)
+            .expect("algorithm not registered")
+    }
+
+    /// Split an object file name into the algorithm and the expected hex encoded hash.
+    pub fn from_object_name(name: &str) -> anyhow::Result<(Self, &str)> {
+        let (typ, hash) = name
+            .split_once('-')
+            .ok_or_else(|| anyhow!("not an object file name: {}", name))?;
+        let alg = Self::from_type(typ)?;
+        if hash.len() != alg.output_size() * 2 {
+            return Err(anyhow!("invalid hash length in object file name: {}", name));
+        }
+        Ok((alg, hash))
+    }
+
+    /// Hash length in bytes
+    pub fn output_size(self) -> usize {
+        match self {
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 | HashAlgorithm::Blake3 => 32,
+            HashAlgorithm::Sha512 => 64,
+        }
+    }
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            HashAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+}
+
+pub enum Hasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha1(h) => h.update(data),
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha512(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    /// Returns the upper hex encoded hash.
+    pub fn finalize(self) -> String {
+        match self {
+            Hasher::Sha1(h) => hex::encode_upper(h.finalize()),
+            Hasher::Sha256(h) => hex::encode_upper(h.finalize()),
+            Hasher::Sha512(h) => hex::encode_upper(h.finalize()),
+            Hasher::Blake3(h) => hex::encode_upper(h.finalize().as_bytes()),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hashes of `abc` for every registered algorithm
+    const ABC: [(HashAlgorithm, &str); 4] = [
+        (
+            HashAlgorithm::Sha1,
+            "A9993E364706816ABA3E25717850C26C9CD0D89D",
+        ),
+        (
+            HashAlgorithm::Sha256,
+            "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD",
+        ),
+        (
+            HashAlgorithm::Sha512,
+            "DDAF35A193617ABACC417349AE20413112E6FA4E89A97EA20A9EEEE64B55D39A\
+             2192992A274FC1A836BA3C23A3FEEBBD454D4423643CE80E2A9AC94FA54CA49F",
+        ),
+        (
+            HashAlgorithm::Blake3,
+            "6437B3AC38465133FFB63B75273A8DB548C558465D79DB03FD359C6CD5BD9D85",
+        ),
+    ];
+
+    #[test]
+    fn hasher_of_each_type() {
+        for (typ, alg) in REGISTRY {
+            let (_, expected) = ABC.iter().find(|(a, _)| *a == alg).unwrap();
+            let mut h = alg.hasher();
+            h.update(b"abc");
+            let hash = h.finalize();
+            assert_eq!(&hash, expected, "{}", typ);
+            assert_eq!(hash.len(), alg.output_size() * 2, "{}", typ);
+        }
+    }
+
+    #[test]
+    fn from_object_name_of_each_type() {
+        for (typ, alg) in REGISTRY {
+            assert_eq!(HashAlgorithm::from_type(typ).unwrap(), alg);
+            assert_eq!(alg.type_name(), typ);
+            let hash = "0".repeat(alg.output_size() * 2);
+            let name = format!("{}-{}", typ, hash);
+            assert_eq!(
+                HashAlgorithm::from_object_name(&name).unwrap(),
+                (alg, hash.as_str())
+            );
+            // a hash of another length belongs to another algorithm
+            let name = format!("{}-{}", typ, "0".repeat(alg.output_size() * 2 + 2));
+            assert!(HashAlgorithm::from_object_name(&name).is_err());
+        }
+        assert!(HashAlgorithm::from_object_name("XX-00").is_err());
+        assert!(HashAlgorithm::from_object_name("S2").is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use crate::archive::list_kbi_files;
+use crate::kbi_verification::{Resolved, load_kbi, resolve};
+use chrono::{DateTime, Local};
+use std::path::Path;
+use std::process::exit;
+
+pub fn file_history(backups: String, path: String) {
+    let kbi_files = match list_kbi_files(&backups) {
+        Ok(v) => v,
+        Err(why) => {
+            tracing::error!("failed to list backup files: {}", why);
+            exit(1);
+        }
+    };
+    let path = path.trim_matches('/');
+
+    // (backup time, backup file name, object of the file in this backup)
+    let mut versions: Vec<(DateTime<Local>, String, Option<String>)> = Vec::new();
+    for (t, file_name) in kbi_files {
+        let backup_info = match load_kbi(&Path::new(&backups).join(&file_name).to_string_lossy()) {
+            Ok(v) => v,
+            Err(why) => {
+                tracing::error!("error decoding kbi file {}: {}", &file_name, why);
+                continue;
+            }
+        };
+        let t = t.unwrap_or_else(|| backup_info.backup_time.0.with_timezone(&Local));
+        let object = match resolve(&backup_info.object_collection2, path) {
+            Some(Resolved::File(elem)) => Some(elem.identifier.to_string()),
+            _ => None,
+        };
+        versions.push((t, file_name, object));
+    }
+    versions.sort_by_key(|v| v.0);
+
+    // whether the file has been in any backup so far, and whether it is in the previous one
+    let mut found = false;
+    let mut present = false;
+    let mut i = 0;
+    while i < versions.len() {
+        // collapse runs of backups with identical object
+        let run = versions[i..]
+            .iter()
+            .take_while(|v| v.2 == versions[i].2)
+            .count();
+        let (t, file_name, object) = &versions[i];
+        let status = match (object, present, found) {
+            (None, false, _) => None,
+            (None, true, _) => Some("deleted"),
+            (Some(_), false, false) => Some("added"),
+            (Some(_), false, true) => Some("re-added"),
+            (Some(_), true, _) => Some("changed"),
+        };
+        if let Some(status) = status {
+            println!(
+                "{} {} {} {}",
+                t.format("%Y-%m-%d %H:%M:%S"),
+                file_name,
+                object.as_deref().unwrap_or("-"),
+                status
+            );
+            if run > 1 {
+                println!(
+                    "    {} in {} more backups, until {}",
+                    if object.is_some() {
+                        "unchanged"
+                    } else {
+                        "absent"
+                    },
+                    run - 1,
+                    versions[i + run - 1].1
+                );
+            }
+        }
+        present = object.is_some();
+        found |= present;
+        i += run;
+    }
+    if !found {
+        tracing::error!("{} is not found in any backup", path);
+        exit(1);
+    }
+}
@@ -0,0 +1,232 @@
+use crate::archive::{parse_archive_time_from_filename, parse_backup_name_from_filename};
+use crate::backup::{BackupStats, load_class_descs, store_reader};
+use crate::hash_algorithm::HashAlgorithm;
+use crate::java_objects::{
+    JavaHashMap, KbiClassDescs, ObjectCollection2, ObjectElement, SavedIncBackupV1, ZonedDateTime,
+};
+use crate::kbi_verification::{Resolved, load_kbi, resolve, write_kbi};
+use crate::move_file::move_file;
+use crate::repo_verification::{hash_file, hash_reader};
+use anyhow::anyhow;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use zip::ZipArchive;
+
+/// Directory tree being built from zip entries
+#[derive(Default)]
+struct DirNode {
+    files: HashMap<String, ObjectElement>,
+    dirs: HashMap<String, DirNode>,
+}
+
+impl DirNode {
+    fn into_collection(self, name: String) -> ObjectCollection2 {
+        ObjectCollection2 {
+            name,
+            elements: JavaHashMap::from(self.files),
+            sub_collections: JavaHashMap::from(
+                self.dirs
+                    .into_iter()
+                    .map(|(k, v)| (k.clone(), v.into_collection(k)))
+                    .collect::<HashMap<_, _>>(),
+            ),
+        }
+    }
+}
+
+pub fn import_zips(
+    incr_repo: String,
+    backups: String,
+    zip_paths: Vec<String>,
+    root_name: Option<String>,
+    template: Option<String>,
+    delete: bool,
+    archive_to: Option<String>,
+) {
+    let descs = match load_class_descs(&backups, template) {
+        Ok(v) => v,
+        Err(why) => {
+            tracing::error!("cannot get class descriptors of .kbi files: {}", why);
+            exit(1);
+        }
+    };
+    let mut failed = 0usize;
+    for zip_path in zip_paths {
+        let kbi_path = match import_zip(&zip_path, &incr_repo, &backups, &root_name, &descs) {
+            Ok(v) => v,
+            Err(why) => {
+                tracing::error!("error importing {}: {}", &zip_path, why);
+                failed += 1;
+                continue;
+            }
+        };
+        if let Err(why) = verify_import(&zip_path, &kbi_path, &incr_repo) {
+            tracing::error!(
+                "imported backup {} does not match {}: {}",
+                kbi_path.display(),
+                &zip_path,
+                why
+            );
+            // a backup known not to match its zip must not be taken for a real one
+            match fs::remove_file(&kbi_path) {
+                Ok(()) => tracing::info!("removed: {}", kbi_path.display()),
+                Err(why) => {
+                    tracing::error!("error removing {}: {}", kbi_path.display(), why)
+                }
+            }
+            failed += 1;
+            continue;
+        }
+        tracing::info!("imported {} as {}", &zip_path, kbi_path.display());
+        if delete {
+            match fs::remove_file(&zip_path) {
+                Ok(()) => tracing::info!("deleted: {}", &zip_path),
+                Err(why) => tracing::error!("error deleting {}: {}", &zip_path, why),
+            }
+        } else if let Some(archive_to) = &archive_to {
+            let file_name = Path::new(&zip_path).file_name().unwrap_or_default();
+            match move_file(Path::new(&zip_path), &Path::new(archive_to).join(file_name)) {
+                Ok(()) => tracing::info!("archived: {}", &zip_path),
+                Err(why) => tracing::error!("error archiving {}: {}", &zip_path, why),
+            }
+        }
+    }
+    if failed > 0 {
+        tracing::error!("{} zip files failed to import", failed);
+        exit(1);
+    }
+}
+
+fn import_zip(
+    zip_path: &str,
+    incr_repo: &str,
+    backups: &str,
+    root_name: &Option<String>,
+    descs: &KbiClassDescs,
+) -> anyhow::Result<PathBuf> {
+    let file_name = Path::new(zip_path)
+        .file_name()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let backup_time = parse_archive_time_from_filename(&file_name)?;
+    let backup_name = parse_backup_name_from_filename(&file_name)?;
+    let kbi_path = Path::new(backups).join(format!(
+        "incremental-{}_{}.kbi",
+        backup_time.format("%Y-%m-%d_%H-%M-%S"),
+        backup_name
+    ));
+    if kbi_path.exists() {
+        return Err(anyhow!(
+            "backup file already exists: {}",
+            kbi_path.display()
+        ));
+    }
+
+    let mut archive = ZipArchive::new(File::open(zip_path)?)?;
+    let mut stats = BackupStats::default();
+    let mut root = DirNode::default();
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i)?;
+        let path = match entry.enclosed_name() {
+            Some(v) => v,
+            None => return Err(anyhow!("unsafe path in zip: {:?}", entry.name())),
+        };
+        let mut components: Vec<String> = path
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        let file_name = match (entry.is_dir(), components.pop()) {
+            (false, Some(v)) => Some(v),
+            (true, Some(v)) => {
+                components.push(v);
+                None
+            }
+            (_, None) => continue,
+        };
+        let mut dir = &mut root;
+        for c in components {
+            dir = dir.dirs.entry(c).or_default();
+        }
+        if let Some(file_name) = file_name {
+            let identifier = store_reader(entry, Path::new(incr_repo), &mut stats)?;
+            dir.files.insert(
+                file_name.clone(),
+                ObjectElement {
+                    name: file_name,
+                    identifier,
+                },
+            );
+        }
+    }
+
+    let object_collection2 = match root_name {
+        Some(name) => root.into_collection(name.clone()),
+        None => {
+            // the zip is expected to contain a single directory, which is the world
+            if root.dirs.len() != 1 || !root.files.is_empty() {
+                return Err(anyhow!(
+                    "zip does not contain a single top-level directory, specify one with --root-name"
+                ));
+            }
+            let (name, node) = root.dirs.into_iter().next().unwrap();
+            node.into_collection(name)
+        }
+    };
+    let backup_info = SavedIncBackupV1 {
+        object_collection2,
+        backup_name: backup_name.to_string(),
+        backup_time: ZonedDateTime(backup_time.fixed_offset(), None),
+        total_size_bytes: stats.total_size_bytes,
+        increased_size_bytes: stats.increased_size_bytes,
+        files_added: stats.files_added,
+        total_files: stats.total_files,
+    };
+    write_kbi(&kbi_path, &backup_info, descs)?;
+    Ok(kbi_path)
+}
+
+/// Check every file in the zip against the object referenced in the written .kbi file.
+fn verify_import(zip_path: &str, kbi_path: &Path, incr_repo: &str) -> anyhow::Result<()> {
+    let backup_info = load_kbi(&kbi_path.to_string_lossy())?;
+    let root = &backup_info.object_collection2;
+    let mut archive = ZipArchive::new(File::open(zip_path)?)?;
+    let mut files = 0;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        if entry.is_dir() {
+            continue;
+        }
+        let path = entry
+            .enclosed_name()
+            .ok_or_else(|| anyhow!("unsafe path in zip: {:?}", entry.name()))?;
+        let path = path.to_string_lossy().replace('\\', "/");
+        // with --root-name, entries in the zip are inside the root collection
+        let elem = match resolve(root, &path)
+            .or_else(|| resolve(root, &format!("{}/{}", root.name, path)))
+        {
+            Some(Resolved::File(elem)) => elem,
+            _ => return Err(anyhow!("{} is not in the backup", path)),
+        };
+        let alg = HashAlgorithm::from_type(&elem.identifier.typ)?;
+        let expected_hash = hex::encode_upper(&elem.identifier.hash);
+        if hash_reader(alg, &mut entry)? != expected_hash {
+            return Err(anyhow!("hash of {} does not match the backup", path));
+        }
+        let obj_path = Path::new(incr_repo).join(elem.identifier.to_string());
+        if hash_file(alg, obj_path)? != expected_hash {
+            return Err(anyhow!("object of {} is corrupted", path));
+        }
+        files += 1;
+    }
+    if files != backup_info.total_files {
+        return Err(anyhow!(
+            "zip has {} files, but the backup has {}",
+            files,
+            backup_info.total_files
+        ));
+    }
+    Ok(())
+}
@@ -1,78 +1,124 @@
 //! Java objects in KBackup-Fabric, with encode and decode logics
 //! There are some known issues:
-//! - `dateTime` is not implemented. The format is way too complex,
-//!   and this field is not necessarily needed. So skip for now.
 //! - The implementation of HashMap is not optimal.
 //!   The recommended way in doc is to use derive with extract.
 //!   But this seems buggy and I cannot get it work. So just use custom type for now.
 //!
-use jaded::{ConversionError, ConversionResult, FromJava, Value};
+use crate::java_stream::{ClassDesc, ObjectWriter, Slot, read_class_descs};
+use anyhow::anyhow;
+use chrono::{
+    DateTime, Datelike, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Timelike,
+};
+use jaded::{AnnotationIter, ConversionError, ConversionResult, FromJava, Value};
 use serde::{Serialize, Serializer};
 use std::collections::HashMap;
-use std::fmt::{Display, Formatter, Write};
+use std::fmt::{Display, Formatter, Write as _};
 use std::hash::Hash;
+use std::io;
+use std::io::{Read, Write};
 
-// #[derive(Debug, Clone)]
-// pub struct ZonedDateTime {
-//     // #[jaded(field = "dateTime")]
-//     // pub date_time: LocalDateTime,
-// }
-//
-// impl FromJava for ZonedDateTime {
-//     fn from_value(value: &Value) -> ConversionResult<Self> {
-//         match value {
-//             Value::Object(obj) => match obj.get_annotation(0) {
-//                 Some(mut it) => {
-//                     let year = it.read_i32()?;
-//                     let month = it.read_u8()?;
-//                     let day = it.read_u8()?;
-//                     let mut hour = it.read_u8()? as i8;
-//                     let mut minute: i8 = 0;
-//                     let mut second: i8 = 0;
-//                     let mut nano: i32 = 0;
-//                     if hour < 0 {
-//                         hour = !hour
-//                     } else {
-//                         minute = it.read_u8()? as i8;
-//                         if minute < 0 {
-//                             minute = !minute
-//                         } else {
-//                             second = it.read_u8()? as i8;
-//                             if second < 0 {
-//                                 second = !second
-//                             } else {
-//                                 nano = it.read_i32()?;
-//                             }
-//                         }
-//                     }
-//                     let offset_byte = it.read_u8()?;
-//                     let offset_seconds = if offset_byte == 127 {
-//                         it.read_i32()?
-//                     } else {
-//                         offset_byte as i32 * 900
-//                     };
-//                     let zone_type = it.read_u8()?;
-//                     tracing::info!(
-//                         "ymdhms: {} {} {} {} {} {} {} zt: {}",
-//                         year,
-//                         month,
-//                         day,
-//                         hour,
-//                         minute,
-//                         second,
-//                         nano,
-//                         zone_type,
-//                     );
-//                     Ok(ZonedDateTime {})
-//                 }
-//                 None => Err(ConversionError::InvalidType(
-//                     "expected object with annotations",
-//                 )),
-//             },
-//             _ => Err(ConversionError::InvalidType("expected object")),
-//         }
-//     }
-// }
+/// `java.time.ZonedDateTime`, which is serialized through the externalized `java.time.Ser` proxy
+/// The zone region, e.g. `Asia/Shanghai`, is kept only to be written back; `None` if the zone is the offset.
+#[derive(Debug, Clone)]
+pub struct ZonedDateTime(pub DateTime<FixedOffset>, pub Option<String>);
+
+// type tags written by java.time.Ser
+const ZONE_DATE_TIME_TYPE: u8 = 6;
+const ZONE_REGION_TYPE: u8 = 7;
+const ZONE_OFFSET_TYPE: u8 = 8;
+
+impl FromJava for ZonedDateTime {
+    fn from_value(value: &Value) -> ConversionResult<Self> {
+        match value {
+            Value::Object(obj) => match obj.get_annotation(0) {
+                Some(mut it) => {
+                    if it.read_u8()? != ZONE_DATE_TIME_TYPE {
+                        return Err(ConversionError::InvalidType(
+                            "expected java.time.Ser of ZonedDateTime",
+                        ));
+                    }
+                    let date_time = read_local_date_time(&mut it)?;
+                    let offset = read_zone_offset(&mut it)?;
+                    let region = match it.read_u8()? {
+                        ZONE_REGION_TYPE => Some(read_utf(&mut it)?),
+                        ZONE_OFFSET_TYPE => read_zone_offset(&mut it).map(|_| None)?,
+                        _ => return Err(ConversionError::InvalidType("unknown ZoneId type")),
+                    };
+                    match offset.from_local_datetime(&date_time).single() {
+                        Some(v) => Ok(ZonedDateTime(v, region)),
+                        None => Err(ConversionError::InvalidType("invalid local time")),
+                    }
+                }
+                None => Err(ConversionError::InvalidType(
+                    "expected object with annotations",
+                )),
+            },
+            _ => Err(ConversionError::InvalidType("expected object")),
+        }
+    }
+}
+
+impl Serialize for ZonedDateTime {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.0.to_rfc3339())
+    }
+}
+
+/// Java: `LocalDate.writeExternal` followed by `LocalTime.writeExternal`.
+/// Trailing zero time fields are omitted, and the last written one is bitwise negated.
+fn read_local_date_time(it: &mut AnnotationIter) -> ConversionResult<NaiveDateTime> {
+    let year = it.read_i32()?;
+    let month = it.read_u8()?;
+    let day = it.read_u8()?;
+    let mut hour = it.read_u8()? as i8;
+    let mut minute: i8 = 0;
+    let mut second: i8 = 0;
+    let mut nano: i32 = 0;
+    if hour < 0 {
+        hour = !hour
+    } else {
+        minute = it.read_u8()? as i8;
+        if minute < 0 {
+            minute = !minute
+        } else {
+            second = it.read_u8()? as i8;
+            if second < 0 {
+                second = !second
+            } else {
+                nano = it.read_i32()?;
+            }
+        }
+    }
+    let date = NaiveDate::from_ymd_opt(year, month as u32, day as u32)
+        .ok_or(ConversionError::InvalidType("invalid LocalDate"))?;
+    let time = NaiveTime::from_hms_nano_opt(hour as u32, minute as u32, second as u32, nano as u32)
+        .ok_or(ConversionError::InvalidType("invalid LocalTime"))?;
+    Ok(date.and_time(time))
+}
+
+/// Java: `ZoneOffset.writeExternal`
+fn read_zone_offset(it: &mut AnnotationIter) -> ConversionResult<FixedOffset> {
+    let offset_byte = it.read_u8()? as i8;
+    let offset_seconds = if offset_byte == 127 {
+        it.read_i32()?
+    } else {
+        offset_byte as i32 * 900
+    };
+    FixedOffset::east_opt(offset_seconds).ok_or(ConversionError::InvalidType("invalid ZoneOffset"))
+}
+
+/// Java: `DataOutput.writeUTF`
+fn read_utf(it: &mut AnnotationIter) -> ConversionResult<String> {
+    let len = it.read_i16()? as u16;
+    let mut buf = Vec::with_capacity(len as usize);
+    for _ in 0..len {
+        buf.push(it.read_u8()?);
+    }
+    String::from_utf8(buf).map_err(|_| ConversionError::InvalidType("invalid UTF string"))
+}
 
 #[derive(Debug, FromJava, Serialize)]
 pub struct SavedIncBackupV1 {
@@ -80,8 +126,8 @@ pub struct SavedIncBackupV1 {
     pub object_collection2: ObjectCollection2,
     #[jaded(field = "backupName")]
     pub backup_name: String,
-    // #[jaded(field = "backupTime")]
-    // pub backup_time: ZonedDateTime,
+    #[jaded(field = "backupTime")]
+    pub backup_time: ZonedDateTime,
     #[jaded(field = "totalSizeBytes")]
     pub total_size_bytes: i64,
     #[jaded(field = "increasedSizeBytes")]
@@ -173,7 +219,7 @@ impl Display for SingleHashIdentifier {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_str(&self.typ)?;
         f.write_char('-')?;
-        f.write_str(&*hex::encode_upper(&self.hash))
+        f.write_str(&hex::encode_upper(&self.hash))
     }
 }
 
@@ -185,3 +231,366 @@ impl Serialize for SingleHashIdentifier {
         serializer.serialize_str(&self.to_string())
     }
 }
+
+/// Class descriptors of objects in a .kbi file.
+/// Classes of KBackup-Fabric are recognized by their field names, like how `FromJava` reads them.
+#[derive(Debug, Clone)]
+pub struct KbiClassDescs {
+    pub saved_inc_backup: ClassDesc,
+    pub object_collection: ClassDesc,
+    pub object_element: ClassDesc,
+    pub hash_identifier: ClassDesc,
+    pub hash_map: ClassDesc,
+    pub java_time_ser: ClassDesc,
+}
+
+impl KbiClassDescs {
+    /// Copy class descriptors from an existing .kbi file written by KBackup-Fabric
+    pub fn from_kbi<R: Read>(r: R) -> anyhow::Result<Self> {
+        let descs = read_class_descs(r)?;
+        let find = |field: &str| {
+            descs
+                .iter()
+                .find(|d| d.has_field(field))
+                .cloned()
+                .ok_or_else(|| anyhow!("no class with field `{}` in kbi file", field))
+        };
+        let find_jdk = |name: &str| descs.iter().find(|d| d.name == name).cloned();
+        Ok(KbiClassDescs {
+            saved_inc_backup: find("objectCollection2")?,
+            object_collection: find("subCollections")?,
+            object_element: find("identifier")?,
+            hash_identifier: find("hash")?,
+            hash_map: find_jdk("java.util.HashMap").unwrap_or_else(ClassDesc::hash_map),
+            java_time_ser: find_jdk("java.time.Ser").unwrap_or_else(ClassDesc::java_time_ser),
+        })
+    }
+}
+
+/// Encode a Rust value as the Java object it is decoded from
+pub trait ToJava {
+    fn to_java<W: Write>(&self, w: &mut ObjectWriter<W>, descs: &KbiClassDescs) -> io::Result<()>;
+}
+
+fn unknown_slot(desc: &ClassDesc) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected field or data in class {}", desc.name),
+    )
+}
+
+impl ToJava for String {
+    fn to_java<W: Write>(&self, w: &mut ObjectWriter<W>, _: &KbiClassDescs) -> io::Result<()> {
+        w.write_string(self)
+    }
+}
+
+impl ToJava for SavedIncBackupV1 {
+    fn to_java<W: Write>(&self, w: &mut ObjectWriter<W>, descs: &KbiClassDescs) -> io::Result<()> {
+        let desc = &descs.saved_inc_backup;
+        w.write_object(desc, |w, slot| match slot {
+            Slot::Field("objectCollection2") => self.object_collection2.to_java(w, descs),
+            Slot::Field("backupName") => w.write_string(&self.backup_name),
+            Slot::Field("backupTime") => self.backup_time.to_java(w, descs),
+            Slot::Field("totalSizeBytes") => w.write_i64(self.total_size_bytes),
+            Slot::Field("increasedSizeBytes") => w.write_i64(self.increased_size_bytes),
+            Slot::Field("filesAdded") => w.write_i32(self.files_added),
+            Slot::Field("totalFiles") => w.write_i32(self.total_files),
+            _ => Err(unknown_slot(desc)),
+        })
+    }
+}
+
+impl ToJava for ObjectCollection2 {
+    fn to_java<W: Write>(&self, w: &mut ObjectWriter<W>, descs: &KbiClassDescs) -> io::Result<()> {
+        let desc = &descs.object_collection;
+        w.write_object(desc, |w, slot| match slot {
+            Slot::Field("name") => w.write_string(&self.name),
+            Slot::Field("elements") => self.elements.to_java(w, descs),
+            Slot::Field("subCollections") => self.sub_collections.to_java(w, descs),
+            _ => Err(unknown_slot(desc)),
+        })
+    }
+}
+
+impl ToJava for ObjectElement {
+    fn to_java<W: Write>(&self, w: &mut ObjectWriter<W>, descs: &KbiClassDescs) -> io::Result<()> {
+        let desc = &descs.object_element;
+        w.write_object(desc, |w, slot| match slot {
+            Slot::Field("name") => w.write_string(&self.name),
+            Slot::Field("identifier") => self.identifier.to_java(w, descs),
+            _ => Err(unknown_slot(desc)),
+        })
+    }
+}
+
+impl ToJava for SingleHashIdentifier {
+    fn to_java<W: Write>(&self, w: &mut ObjectWriter<W>, descs: &KbiClassDescs) -> io::Result<()> {
+        let desc = &descs.hash_identifier;
+        w.write_object(desc, |w, slot| match slot {
+            Slot::Field("type") => w.write_string(&self.typ),
+            Slot::Field("hash") => w.write_byte_array(&self.hash),
+            _ => Err(unknown_slot(desc)),
+        })
+    }
+}
+
+impl<V: ToJava> ToJava for JavaHashMap<String, V> {
+    fn to_java<W: Write>(&self, w: &mut ObjectWriter<W>, descs: &KbiClassDescs) -> io::Result<()> {
+        const LOAD_FACTOR: f32 = 0.75;
+        // Java: the table starts at 16 and doubles once the size exceeds capacity * loadFactor
+        let mut capacity = 16usize;
+        while self.0.len() as f32 > capacity as f32 * LOAD_FACTOR {
+            capacity *= 2;
+        }
+        // Java: entries are written in the order of their buckets
+        let bucket = |k: &String| {
+            let h = k
+                .encode_utf16()
+                .fold(0i32, |h, c| h.wrapping_mul(31).wrapping_add(c as i32));
+            (h ^ ((h as u32) >> 16) as i32) as usize & (capacity - 1)
+        };
+        let mut entries: Vec<_> = self.0.iter().collect();
+        entries.sort_by(|a, b| bucket(a.0).cmp(&bucket(b.0)).then_with(|| a.0.cmp(b.0)));
+        let desc = &descs.hash_map;
+        w.write_object(desc, |w, slot| match slot {
+            Slot::Field("loadFactor") => w.write_f32(LOAD_FACTOR),
+            // the table of an empty map is never allocated, leaving the threshold at 0
+            Slot::Field("threshold") if entries.is_empty() => w.write_i32(0),
+            Slot::Field("threshold") => w.write_i32((capacity as f32 * LOAD_FACTOR) as i32),
+            Slot::Annotation(class_name) if class_name == desc.name => {
+                w.write_i32(capacity as i32)?;
+                w.write_i32(entries.len() as i32)?;
+                for (k, v) in entries.iter() {
+                    k.to_java(w, descs)?;
+                    v.to_java(w, descs)?;
+                }
+                Ok(())
+            }
+            _ => Err(unknown_slot(desc)),
+        })
+    }
+}
+
+impl ToJava for ZonedDateTime {
+    fn to_java<W: Write>(&self, w: &mut ObjectWriter<W>, descs: &KbiClassDescs) -> io::Result<()> {
+        let desc = &descs.java_time_ser;
+        w.write_object(desc, |w, slot| match slot {
+            Slot::External => {
+                w.write_u8(ZONE_DATE_TIME_TYPE)?;
+                write_local_date_time(w, &self.0.naive_local())?;
+                write_zone_offset(w, self.0.offset())?;
+                match &self.1 {
+                    Some(region) => {
+                        w.write_u8(ZONE_REGION_TYPE)?;
+                        w.write_utf(region)
+                    }
+                    // the zone region is unknown, use the offset as ZoneId
+                    None => {
+                        w.write_u8(ZONE_OFFSET_TYPE)?;
+                        write_zone_offset(w, self.0.offset())
+                    }
+                }
+            }
+            _ => Err(unknown_slot(desc)),
+        })
+    }
+}
+
+/// Java: `LocalDate.writeExternal` followed by `LocalTime.writeExternal`
+fn write_local_date_time<W: Write>(w: &mut ObjectWriter<W>, t: &NaiveDateTime) -> io::Result<()> {
+    w.write_i32(t.year())?;
+    w.write_u8(t.month() as u8)?;
+    w.write_u8(t.day() as u8)?;
+    let (hour, minute, second, nano) = (
+        t.hour() as u8,
+        t.minute() as u8,
+        t.second() as u8,
+        t.nanosecond(),
+    );
+    if nano == 0 {
+        if second == 0 {
+            if minute == 0 {
+                w.write_u8(!hour)
+            } else {
+                w.write_u8(hour)?;
+                w.write_u8(!minute)
+            }
+        } else {
+            w.write_u8(hour)?;
+            w.write_u8(minute)?;
+            w.write_u8(!second)
+        }
+    } else {
+        w.write_u8(hour)?;
+        w.write_u8(minute)?;
+        w.write_u8(second)?;
+        w.write_i32(nano as i32)
+    }
+}
+
+/// Java: `ZoneOffset.writeExternal`
+fn write_zone_offset<W: Write>(w: &mut ObjectWriter<W>, offset: &FixedOffset) -> io::Result<()> {
+    let offset_seconds = offset.local_minus_utc();
+    if offset_seconds % 900 == 0 {
+        w.write_u8((offset_seconds / 900) as i8 as u8)
+    } else {
+        w.write_u8(127)?;
+        w.write_i32(offset_seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::java_stream::{FieldDesc, SC_SERIALIZABLE};
+
+    fn class(name: &str, uid: i64, fields: &[(u8, &str, Option<&str>)]) -> ClassDesc {
+        ClassDesc {
+            name: name.to_string(),
+            serial_version_uid: uid,
+            flags: SC_SERIALIZABLE,
+            fields: fields
+                .iter()
+                .map(|(t, n, c)| FieldDesc {
+                    type_code: *t,
+                    name: n.to_string(),
+                    class_name: c.map(str::to_string),
+                })
+                .collect(),
+            super_class: None,
+        }
+    }
+
+    fn test_descs() -> KbiClassDescs {
+        const STRING: Option<&str> = Some("Ljava/lang/String;");
+        const MAP: Option<&str> = Some("Ljava/util/Map;");
+        let mut hash_identifier = class("test.Sha256Identifier", 3, &[]);
+        hash_identifier.super_class = Some(Box::new(class(
+            "test.SingleHashIdentifier",
+            4,
+            &[(b'[', "hash", Some("[B")), (b'L', "type", STRING)],
+        )));
+        KbiClassDescs {
+            saved_inc_backup: class(
+                "test.SavedIncBackupV1",
+                1,
+                &[
+                    (b'I', "filesAdded", None),
+                    (b'J', "increasedSizeBytes", None),
+                    (b'I', "totalFiles", None),
+                    (b'J', "totalSizeBytes", None),
+                    (b'L', "backupName", STRING),
+                    (b'L', "backupTime", Some("Ljava/time/ZonedDateTime;")),
+                    (b'L', "objectCollection2", Some("Ltest/ObjectCollection2;")),
+                ],
+            ),
+            object_collection: class(
+                "test.ObjectCollection2",
+                2,
+                &[
+                    (b'L', "elements", MAP),
+                    (b'L', "name", STRING),
+                    (b'L', "subCollections", MAP),
+                ],
+            ),
+            object_element: class(
+                "test.ObjectElement",
+                5,
+                &[
+                    (b'L', "identifier", Some("Ltest/ObjectIdentifier;")),
+                    (b'L', "name", STRING),
+                ],
+            ),
+            hash_identifier,
+            hash_map: ClassDesc::hash_map(),
+            java_time_ser: ClassDesc::java_time_ser(),
+        }
+    }
+
+    fn element(name: &str, hash: &[u8]) -> (String, ObjectElement) {
+        let elem = ObjectElement {
+            name: name.to_string(),
+            identifier: SingleHashIdentifier {
+                typ: "S2".to_string(),
+                hash: hash.to_vec(),
+            },
+        };
+        (name.to_string(), elem)
+    }
+
+    #[test]
+    fn round_trip_saved_inc_backup() {
+        let region = ObjectCollection2 {
+            name: "region".to_string(),
+            elements: HashMap::from([element("r.0.0.mca", &[1, 2, 3]), element("r.0.1.mca", &[4])])
+                .into(),
+            sub_collections: HashMap::new().into(),
+        };
+        let backup = SavedIncBackupV1 {
+            object_collection2: ObjectCollection2 {
+                name: "world".to_string(),
+                elements: HashMap::from([element("level.dat", &[0xff; 32])]).into(),
+                sub_collections: HashMap::from([("region".to_string(), region)]).into(),
+            },
+            backup_name: "测试 backup".to_string(),
+            backup_time: ZonedDateTime(
+                DateTime::parse_from_rfc3339("2024-03-05T12:30:00.5+05:30").unwrap(),
+                None,
+            ),
+            total_size_bytes: 1 << 40,
+            increased_size_bytes: 1234,
+            files_added: 2,
+            total_files: 3,
+        };
+
+        let descs = test_descs();
+        let mut writer = ObjectWriter::new(Vec::new()).unwrap();
+        backup.to_java(&mut writer, &descs).unwrap();
+        let data = writer.into_inner().unwrap();
+
+        let decoded: SavedIncBackupV1 = jaded::Parser::new(&data[..]).unwrap().read_as().unwrap();
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::to_value(&backup).unwrap()
+        );
+
+        let read_descs = KbiClassDescs::from_kbi(&data[..]).unwrap();
+        assert_eq!(read_descs.saved_inc_backup.serial_version_uid, 1);
+        assert_eq!(read_descs.hash_identifier.name, "test.Sha256Identifier");
+        assert_eq!(read_descs.hash_identifier.hierarchy().len(), 2);
+    }
+
+    #[test]
+    fn decode_zoned_date_time_with_zone_region() {
+        // ObjectOutputStream.writeObject(ZonedDateTime.of(2024, 3, 5, 12, 30, 0, 500_000_000,
+        // ZoneId.of("Asia/Shanghai"))) from JDK 17
+        const DATA: &[u8] = &[
+            0xac, 0xed, 0x00, 0x05, 0x73, 0x72, 0x00, 0x0d, 0x6a, 0x61, 0x76, 0x61, 0x2e, 0x74,
+            0x69, 0x6d, 0x65, 0x2e, 0x53, 0x65, 0x72, 0x95, 0x5d, 0x84, 0xba, 0x1b, 0x22, 0x48,
+            0xb2, 0x0c, 0x00, 0x00, 0x78, 0x70, 0x77, 0x1f, 0x06, 0x00, 0x00, 0x07, 0xe8, 0x03,
+            0x05, 0x0c, 0x1e, 0x00, 0x1d, 0xcd, 0x65, 0x00, 0x20, 0x07, 0x00, 0x0d, 0x41, 0x73,
+            0x69, 0x61, 0x2f, 0x53, 0x68, 0x61, 0x6e, 0x67, 0x68, 0x61, 0x69, 0x78,
+        ];
+
+        let decoded: ZonedDateTime = jaded::Parser::new(DATA).unwrap().read_as().unwrap();
+        assert_eq!(
+            decoded.0,
+            DateTime::parse_from_rfc3339("2024-03-05T12:30:00.5+08:00").unwrap()
+        );
+        assert_eq!(decoded.0.offset().local_minus_utc(), 8 * 3600);
+    }
+
+    #[test]
+    fn re_encode_kbi_byte_for_byte() {
+        // written by ObjectOutputStream, see testdata/SavedIncBackupFixture.java
+        let data = include_bytes!("../testdata/saved_inc_backup.kbi");
+        let backup: SavedIncBackupV1 = jaded::Parser::new(&data[..]).unwrap().read_as().unwrap();
+        assert_eq!(backup.backup_time.1.as_deref(), Some("Asia/Shanghai"));
+
+        let descs = KbiClassDescs::from_kbi(&data[..]).unwrap();
+        let mut writer = ObjectWriter::new(Vec::new()).unwrap();
+        backup.to_java(&mut writer, &descs).unwrap();
+        assert_eq!(writer.into_inner().unwrap(), data);
+    }
+}
@@ -0,0 +1,623 @@
+//! A minimal writer of the Java Object Serialization Stream Protocol,
+//! just enough to produce the objects KBackup-Fabric reads.
+//! See https://docs.oracle.com/en/java/javase/17/docs/specs/serialization/protocol.html
+//!
+//! Class descriptors (especially serialVersionUIDs) must match the classes on the Java side,
+//! so they are usually copied from an existing stream with `read_class_descs`.
+use anyhow::anyhow;
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+
+const STREAM_MAGIC: u16 = 0xaced;
+const STREAM_VERSION: u16 = 5;
+const BASE_HANDLE: u32 = 0x7e0000;
+const MAX_BLOCK_SIZE: usize = 1024;
+
+const TC_NULL: u8 = 0x70;
+const TC_REFERENCE: u8 = 0x71;
+const TC_CLASSDESC: u8 = 0x72;
+const TC_OBJECT: u8 = 0x73;
+const TC_STRING: u8 = 0x74;
+const TC_ARRAY: u8 = 0x75;
+const TC_CLASS: u8 = 0x76;
+const TC_BLOCKDATA: u8 = 0x77;
+const TC_ENDBLOCKDATA: u8 = 0x78;
+const TC_RESET: u8 = 0x79;
+const TC_BLOCKDATALONG: u8 = 0x7a;
+const TC_LONGSTRING: u8 = 0x7c;
+const TC_ENUM: u8 = 0x7e;
+
+pub const SC_WRITE_METHOD: u8 = 0x01;
+pub const SC_SERIALIZABLE: u8 = 0x02;
+pub const SC_EXTERNALIZABLE: u8 = 0x04;
+pub const SC_BLOCK_DATA: u8 = 0x08;
+
+#[derive(Debug, Clone)]
+pub struct FieldDesc {
+    /// Java type code, e.g. `I` for int, `L` for object, `[` for array
+    pub type_code: u8,
+    pub name: String,
+    /// Type signature of object and array fields, e.g. `Ljava/lang/String;`
+    pub class_name: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClassDesc {
+    pub name: String,
+    pub serial_version_uid: i64,
+    pub flags: u8,
+    pub fields: Vec<FieldDesc>,
+    pub super_class: Option<Box<ClassDesc>>,
+}
+
+impl ClassDesc {
+    /// Classes from the topmost serializable superclass down to this class
+    pub fn hierarchy(&self) -> Vec<&ClassDesc> {
+        let mut result = match &self.super_class {
+            Some(s) => s.hierarchy(),
+            None => Vec::new(),
+        };
+        result.push(self);
+        result
+    }
+
+    pub fn has_field(&self, name: &str) -> bool {
+        self.hierarchy()
+            .iter()
+            .any(|c| c.fields.iter().any(|f| f.name == name))
+    }
+
+    pub fn byte_array() -> ClassDesc {
+        ClassDesc {
+            name: "[B".to_string(),
+            serial_version_uid: 0xacf317f8060854e0_u64 as i64,
+            flags: SC_SERIALIZABLE,
+            fields: Vec::new(),
+            super_class: None,
+        }
+    }
+
+    pub fn hash_map() -> ClassDesc {
+        ClassDesc {
+            name: "java.util.HashMap".to_string(),
+            serial_version_uid: 362498820763181265,
+            flags: SC_SERIALIZABLE | SC_WRITE_METHOD,
+            fields: vec![
+                FieldDesc {
+                    type_code: b'F',
+                    name: "loadFactor".to_string(),
+                    class_name: None,
+                },
+                FieldDesc {
+                    type_code: b'I',
+                    name: "threshold".to_string(),
+                    class_name: None,
+                },
+            ],
+            super_class: None,
+        }
+    }
+
+    /// `java.time.Ser`, the serialization proxy of all `java.time` classes
+    pub fn java_time_ser() -> ClassDesc {
+        ClassDesc {
+            name: "java.time.Ser".to_string(),
+            serial_version_uid: -7683839454370182990,
+            flags: SC_EXTERNALIZABLE | SC_BLOCK_DATA,
+            fields: Vec::new(),
+            super_class: None,
+        }
+    }
+}
+
+/// Where `ObjectWriter::write_object` is in the object being written
+pub enum Slot<'a> {
+    /// A serializable field, which must be written with the type in its descriptor
+    Field(&'a str),
+    /// Custom data written by `writeObject` of the class with the given name, after its fields
+    Annotation(&'a str),
+    /// Data written by `writeExternal`
+    External,
+}
+
+pub struct ObjectWriter<W: Write> {
+    out: W,
+    next_handle: u32,
+    class_handles: HashMap<String, u32>,
+    string_handles: HashMap<String, u32>,
+    /// Pending block data, `Some` if in block data mode
+    block: Option<Vec<u8>>,
+}
+
+impl<W: Write> ObjectWriter<W> {
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(&STREAM_MAGIC.to_be_bytes())?;
+        out.write_all(&STREAM_VERSION.to_be_bytes())?;
+        Ok(ObjectWriter {
+            out,
+            next_handle: BASE_HANDLE,
+            class_handles: HashMap::new(),
+            string_handles: HashMap::new(),
+            block: None,
+        })
+    }
+
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    pub fn write_u8(&mut self, v: u8) -> io::Result<()> {
+        self.write_primitive(&[v])
+    }
+
+    pub fn write_i32(&mut self, v: i32) -> io::Result<()> {
+        self.write_primitive(&v.to_be_bytes())
+    }
+
+    pub fn write_i64(&mut self, v: i64) -> io::Result<()> {
+        self.write_primitive(&v.to_be_bytes())
+    }
+
+    pub fn write_f32(&mut self, v: f32) -> io::Result<()> {
+        self.write_primitive(&v.to_be_bytes())
+    }
+
+    /// Java: `DataOutput.writeUTF`
+    pub fn write_utf(&mut self, s: &str) -> io::Result<()> {
+        let data = encode_modified_utf8(s);
+        if data.len() > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "string too long for writeUTF",
+            ));
+        }
+        self.write_primitive(&(data.len() as u16).to_be_bytes())?;
+        self.write_primitive(&data)
+    }
+
+    /// Write a `java.lang.String`. Equal strings share the same handle.
+    pub fn write_string(&mut self, s: &str) -> io::Result<()> {
+        let saved = self.begin_content()?;
+        if let Some(&h) = self.string_handles.get(s) {
+            self.write_reference(h)?;
+        } else {
+            let h = self.assign_handle();
+            self.string_handles.insert(s.to_string(), h);
+            let data = encode_modified_utf8(s);
+            if data.len() <= u16::MAX as usize {
+                self.out.write_all(&[TC_STRING])?;
+                self.out.write_all(&(data.len() as u16).to_be_bytes())?;
+            } else {
+                self.out.write_all(&[TC_LONGSTRING])?;
+                self.out.write_all(&(data.len() as u64).to_be_bytes())?;
+            }
+            self.out.write_all(&data)?;
+        }
+        self.end_content(saved);
+        Ok(())
+    }
+
+    /// Write a `byte[]`
+    pub fn write_byte_array(&mut self, data: &[u8]) -> io::Result<()> {
+        let saved = self.begin_content()?;
+        self.out.write_all(&[TC_ARRAY])?;
+        self.write_class_desc(&ClassDesc::byte_array())?;
+        self.assign_handle();
+        self.out.write_all(&(data.len() as i32).to_be_bytes())?;
+        self.out.write_all(data)?;
+        self.end_content(saved);
+        Ok(())
+    }
+
+    /// Write an object of class `desc`. `callback` is invoked for every field in the class
+    /// hierarchy in stream order, and for custom data of classes with `writeObject` or `writeExternal`.
+    pub fn write_object<F>(&mut self, desc: &ClassDesc, mut callback: F) -> io::Result<()>
+    where
+        F: FnMut(&mut Self, Slot) -> io::Result<()>,
+    {
+        let saved = self.begin_content()?;
+        self.out.write_all(&[TC_OBJECT])?;
+        self.write_class_desc(desc)?;
+        self.assign_handle();
+        if desc.flags & SC_EXTERNALIZABLE != 0 {
+            if desc.flags & SC_BLOCK_DATA == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "externalizable class without block data",
+                ));
+            }
+            self.block = Some(Vec::new());
+            callback(self, Slot::External)?;
+            self.end_block()?;
+        } else {
+            for class in desc.hierarchy() {
+                for field in class.fields.iter() {
+                    callback(self, Slot::Field(&field.name))?;
+                }
+                if class.flags & SC_WRITE_METHOD != 0 {
+                    self.block = Some(Vec::new());
+                    callback(self, Slot::Annotation(&class.name))?;
+                    self.end_block()?;
+                }
+            }
+        }
+        self.end_content(saved);
+        Ok(())
+    }
+
+    fn write_class_desc(&mut self, desc: &ClassDesc) -> io::Result<()> {
+        if let Some(&h) = self.class_handles.get(&desc.name) {
+            return self.write_reference(h);
+        }
+        self.out.write_all(&[TC_CLASSDESC])?;
+        let h = self.assign_handle();
+        self.class_handles.insert(desc.name.clone(), h);
+        self.write_utf(&desc.name)?;
+        self.out.write_all(&desc.serial_version_uid.to_be_bytes())?;
+        self.out.write_all(&[desc.flags])?;
+        self.out
+            .write_all(&(desc.fields.len() as u16).to_be_bytes())?;
+        for field in desc.fields.iter() {
+            self.out.write_all(&[field.type_code])?;
+            self.write_utf(&field.name)?;
+            if let Some(class_name) = &field.class_name {
+                self.write_string(class_name)?;
+            }
+        }
+        self.out.write_all(&[TC_ENDBLOCKDATA])?;
+        match &desc.super_class {
+            Some(s) => self.write_class_desc(s),
+            None => self.out.write_all(&[TC_NULL]),
+        }
+    }
+
+    fn write_reference(&mut self, handle: u32) -> io::Result<()> {
+        self.out.write_all(&[TC_REFERENCE])?;
+        self.out.write_all(&handle.to_be_bytes())
+    }
+
+    fn assign_handle(&mut self) -> u32 {
+        let h = self.next_handle;
+        self.next_handle += 1;
+        h
+    }
+
+    fn write_primitive(&mut self, data: &[u8]) -> io::Result<()> {
+        match self.block.as_mut() {
+            Some(buf) => {
+                buf.extend_from_slice(data);
+                Ok(())
+            }
+            None => self.out.write_all(data),
+        }
+    }
+
+    /// Objects are never written inside block data, flush pending data and leave block data mode.
+    fn begin_content(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self.block.take() {
+            Some(buf) => {
+                self.write_block_data(&buf)?;
+                Ok(Some(Vec::new()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn end_content(&mut self, saved: Option<Vec<u8>>) {
+        self.block = saved;
+    }
+
+    fn end_block(&mut self) -> io::Result<()> {
+        if let Some(buf) = self.block.take() {
+            self.write_block_data(&buf)?;
+        }
+        self.out.write_all(&[TC_ENDBLOCKDATA])
+    }
+
+    fn write_block_data(&mut self, data: &[u8]) -> io::Result<()> {
+        for chunk in data.chunks(MAX_BLOCK_SIZE) {
+            if chunk.len() <= u8::MAX as usize {
+                self.out.write_all(&[TC_BLOCKDATA, chunk.len() as u8])?;
+            } else {
+                self.out.write_all(&[TC_BLOCKDATALONG])?;
+                self.out.write_all(&(chunk.len() as i32).to_be_bytes())?;
+            }
+            self.out.write_all(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// Java uses "modified UTF-8": NUL is encoded in two bytes,
+/// and supplementary characters are encoded as surrogate pairs.
+fn encode_modified_utf8(s: &str) -> Vec<u8> {
+    let mut result = Vec::with_capacity(s.len());
+    for c in s.encode_utf16() {
+        match c {
+            0x0001..=0x007f => result.push(c as u8),
+            0x0000 | 0x0080..=0x07ff => {
+                result.push(0xc0 | (c >> 6) as u8);
+                result.push(0x80 | (c & 0x3f) as u8);
+            }
+            _ => {
+                result.push(0xe0 | (c >> 12) as u8);
+                result.push(0x80 | ((c >> 6) & 0x3f) as u8);
+                result.push(0x80 | (c & 0x3f) as u8);
+            }
+        }
+    }
+    result
+}
+
+/// Read all class descriptors used by objects in a stream, the most derived class of every object.
+pub fn read_class_descs<R: Read>(r: R) -> anyhow::Result<Vec<ClassDesc>> {
+    let mut scanner = DescScanner {
+        r,
+        handles: Vec::new(),
+        result: Vec::new(),
+    };
+    let mut header = [0u8; 4];
+    scanner.r.read_exact(&mut header)?;
+    if u16::from_be_bytes([header[0], header[1]]) != STREAM_MAGIC {
+        return Err(anyhow!("not a Java serialization stream"));
+    }
+    loop {
+        let mut tc = [0u8; 1];
+        match scanner.r.read(&mut tc)? {
+            0 => break,
+            _ => scanner.read_content(tc[0])?,
+        };
+    }
+    Ok(scanner.result)
+}
+
+enum Scanned {
+    Null,
+    Class(ClassDesc),
+    Other,
+    EndBlock,
+}
+
+enum Handle {
+    Class(ClassDesc),
+    String(String),
+    Other,
+}
+
+struct DescScanner<R: Read> {
+    r: R,
+    handles: Vec<Handle>,
+    result: Vec<ClassDesc>,
+}
+
+impl<R: Read> DescScanner<R> {
+    fn read_content(&mut self, tc: u8) -> anyhow::Result<Scanned> {
+        Ok(match tc {
+            TC_NULL => Scanned::Null,
+            TC_REFERENCE => {
+                let h = self.read_u32()?.wrapping_sub(BASE_HANDLE) as usize;
+                match self.handles.get(h) {
+                    Some(Handle::Class(desc)) => Scanned::Class(desc.clone()),
+                    Some(_) => Scanned::Other,
+                    None => return Err(anyhow!("unknown reference handle")),
+                }
+            }
+            TC_CLASSDESC => Scanned::Class(self.read_class_desc()?),
+            TC_OBJECT => {
+                let desc = self.read_desc_ref()?;
+                self.handles.push(Handle::Other);
+                self.read_object_data(&desc)?;
+                if !self.result.iter().any(|d| d.name == desc.name) {
+                    self.result.push(desc);
+                }
+                Scanned::Other
+            }
+            TC_STRING => {
+                let s = self.read_utf()?;
+                self.handles.push(Handle::String(s));
+                Scanned::Other
+            }
+            TC_LONGSTRING => {
+                self.handles.push(Handle::Other);
+                let n = self.read_u64()?;
+                self.skip(n)?;
+                Scanned::Other
+            }
+            TC_ARRAY => {
+                let desc = self.read_desc_ref()?;
+                self.handles.push(Handle::Other);
+                let n = self.read_u32()? as u64;
+                match desc.name.as_bytes().get(1) {
+                    Some(b'L') | Some(b'[') => {
+                        for _ in 0..n {
+                            let tc = self.read_u8()?;
+                            self.read_content(tc)?;
+                        }
+                    }
+                    Some(&t) => self.skip(n * primitive_size(t)?)?,
+                    None => return Err(anyhow!("invalid array class {}", desc.name)),
+                }
+                Scanned::Other
+            }
+            TC_ENUM => {
+                self.read_desc_ref()?;
+                self.handles.push(Handle::Other);
+                let tc = self.read_u8()?;
+                self.read_content(tc)?;
+                Scanned::Other
+            }
+            TC_CLASS => {
+                self.read_desc_ref()?;
+                self.handles.push(Handle::Other);
+                Scanned::Other
+            }
+            TC_BLOCKDATA => {
+                let n = self.read_u8()? as u64;
+                self.skip(n)?;
+                Scanned::Other
+            }
+            TC_BLOCKDATALONG => {
+                let n = self.read_u32()? as u64;
+                self.skip(n)?;
+                Scanned::Other
+            }
+            TC_ENDBLOCKDATA => Scanned::EndBlock,
+            TC_RESET => {
+                self.handles.clear();
+                Scanned::Other
+            }
+            _ => return Err(anyhow!("unsupported type code 0x{:02x}", tc)),
+        })
+    }
+
+    fn read_class_desc(&mut self) -> anyhow::Result<ClassDesc> {
+        let h = self.handles.len();
+        self.handles.push(Handle::Other);
+        let name = self.read_utf()?;
+        let serial_version_uid = self.read_u64()? as i64;
+        let flags = self.read_u8()?;
+        let n = self.read_u16()?;
+        let mut fields = Vec::with_capacity(n as usize);
+        for _ in 0..n {
+            let type_code = self.read_u8()?;
+            let name = self.read_utf()?;
+            let class_name = match type_code {
+                b'L' | b'[' => Some(self.read_type_string()?),
+                _ => None,
+            };
+            fields.push(FieldDesc {
+                type_code,
+                name,
+                class_name,
+            });
+        }
+        self.skip_annotations()?;
+        let super_class = match self.read_u8()? {
+            TC_NULL => None,
+            tc => match self.read_content(tc)? {
+                Scanned::Class(desc) => Some(Box::new(desc)),
+                _ => return Err(anyhow!("invalid super class of {}", name)),
+            },
+        };
+        let desc = ClassDesc {
+            name,
+            serial_version_uid,
+            flags,
+            fields,
+            super_class,
+        };
+        self.handles[h] = Handle::Class(desc.clone());
+        Ok(desc)
+    }
+
+    fn read_desc_ref(&mut self) -> anyhow::Result<ClassDesc> {
+        let tc = self.read_u8()?;
+        match self.read_content(tc)? {
+            Scanned::Class(desc) => Ok(desc),
+            _ => Err(anyhow!("expected class descriptor")),
+        }
+    }
+
+    /// Type strings of fields are written as normal strings, which may be references.
+    fn read_type_string(&mut self) -> anyhow::Result<String> {
+        match self.read_u8()? {
+            TC_STRING => {
+                let s = self.read_utf()?;
+                self.handles.push(Handle::String(s.clone()));
+                Ok(s)
+            }
+            TC_REFERENCE => {
+                let h = self.read_u32()?.wrapping_sub(BASE_HANDLE) as usize;
+                match self.handles.get(h) {
+                    Some(Handle::String(s)) => Ok(s.clone()),
+                    _ => Err(anyhow!("invalid reference to type string")),
+                }
+            }
+            tc => Err(anyhow!("expected type string, got 0x{:02x}", tc)),
+        }
+    }
+
+    fn read_object_data(&mut self, desc: &ClassDesc) -> anyhow::Result<()> {
+        if desc.flags & SC_EXTERNALIZABLE != 0 {
+            if desc.flags & SC_BLOCK_DATA == 0 {
+                return Err(anyhow!("externalizable class without block data"));
+            }
+            return self.skip_annotations();
+        }
+        for class in desc.hierarchy() {
+            for field in class.fields.iter() {
+                match field.type_code {
+                    b'L' | b'[' => {
+                        let tc = self.read_u8()?;
+                        self.read_content(tc)?;
+                    }
+                    t => self.skip(primitive_size(t)?)?,
+                }
+            }
+            if class.flags & SC_WRITE_METHOD != 0 {
+                self.skip_annotations()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn skip_annotations(&mut self) -> anyhow::Result<()> {
+        loop {
+            let tc = self.read_u8()?;
+            if let Scanned::EndBlock = self.read_content(tc)? {
+                return Ok(());
+            }
+        }
+    }
+
+    fn read_utf(&mut self) -> anyhow::Result<String> {
+        let n = self.read_u16()? as usize;
+        let mut buf = vec![0u8; n];
+        self.r.read_exact(&mut buf)?;
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    fn read_u8(&mut self) -> io::Result<u8> {
+        let mut buf = [0u8; 1];
+        self.r.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn read_u16(&mut self) -> io::Result<u16> {
+        let mut buf = [0u8; 2];
+        self.r.read_exact(&mut buf)?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0u8; 4];
+        self.r.read_exact(&mut buf)?;
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+        self.r.read_exact(&mut buf)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    fn skip(&mut self, n: u64) -> io::Result<()> {
+        let copied = io::copy(&mut (&mut self.r).take(n), &mut io::sink())?;
+        if copied != n {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+}
+
+fn primitive_size(type_code: u8) -> anyhow::Result<u64> {
+    Ok(match type_code {
+        b'B' | b'Z' => 1,
+        b'C' | b'S' => 2,
+        b'I' | b'F' => 4,
+        b'J' | b'D' => 8,
+        _ => return Err(anyhow!("unknown type code {}", type_code as char)),
+    })
+}
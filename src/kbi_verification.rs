@@ -1,58 +1,70 @@
 use crate::java_objects;
-use crate::java_objects::{ObjectCollection2, ObjectElement};
-use crate::repo_verification::verify_files;
-use std::collections::{HashMap, HashSet};
+use crate::java_objects::{KbiClassDescs, ObjectCollection2, ObjectElement, ToJava};
+use crate::java_stream::ObjectWriter;
+use crate::repair::{RepairOptions, repair_objects};
+use crate::repo_verification::{ReadError, VerifyProgress, verify_files};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
 use std::thread;
 
-pub fn verify_kbi<T: IntoIterator<Item = String> + Send>(kbi_paths: T, repo_path: String) {
+pub fn verify_kbi<T: IntoIterator<Item = String> + Send>(
+    kbi_paths: T,
+    repo_path: String,
+    json: bool,
+    repair: Option<RepairOptions>,
+) {
     let (send, recv) = crossbeam::channel::bounded(1024);
-    crossbeam::thread::scope(|s| {
-        s.spawn(move |_| {
+    let progress = VerifyProgress::new();
+    let repo_path = PathBuf::from(repo_path);
+    let report = crossbeam::thread::scope(|s| {
+        let repo_path = &repo_path;
+        let progress2 = progress.clone();
+        let producer = s.spawn(move |_| {
             let mut verified_files = HashSet::new();
+            let mut errors = Vec::new();
             for kbi_path in kbi_paths {
-                let file = File::open(&kbi_path).expect("error opening file");
-                let mut parser = match jaded::Parser::new(file) {
+                let backup_info = match load_kbi(&kbi_path) {
                     Ok(v) => v,
                     Err(why) => {
-                        tracing::error!("error decoding kbi file {}: {}", &kbi_path, why);
+                        tracing::debug!("error decoding kbi file {}: {}", &kbi_path, why);
+                        errors.push(ReadError {
+                            path: kbi_path,
+                            error: why.to_string(),
+                        });
                         continue;
                     }
                 };
-                let backup_info: java_objects::SavedIncBackupV1 = match parser.read_as() {
-                    Ok(v) => v,
-                    Err(why) => {
-                        tracing::error!(
-                            "error decoding backup info from file {}: {}",
-                            &kbi_path,
-                            why
-                        );
-                        continue;
+                traverse_all(repo_path, &backup_info.object_collection2, &mut |pb, s| {
+                    if !verified_files.insert(s.clone()) {
+                        return;
                     }
-                };
-                traverse_all(
-                    &PathBuf::from(&repo_path),
-                    &backup_info.object_collection2,
-                    &mut |pb, s| {
-                        if !verified_files.insert(s.clone()) {
-                            return;
-                        }
-                        send.send((pb, s)).expect("error sending object");
-                    },
-                );
+                    progress2.add_object(fs::metadata(&pb).map(|m| m.len()).unwrap_or(0));
+                    send.send((pb, s)).expect("error sending object");
+                });
             }
+            errors
         });
-        verify_files(0, recv, 0);
+        let mut report = verify_files(0, recv, progress);
+        report
+            .unreadable
+            .extend(producer.join().expect("kbi reader thread panicked"));
+        if let Some(opts) = repair {
+            repair_objects(repo_path, &mut report, &opts);
+        }
+        report
     })
     .unwrap();
+    report.finish(json)
 }
 
-pub fn collect_kbi_objects(kbi_path: String, repo_path: String) -> Vec<(PathBuf, String)> {
-    let file = File::open(kbi_path).expect("error opening file");
-    let mut parser = jaded::Parser::new(file).expect("error decoding file");
-    let backup_info: java_objects::SavedIncBackupV1 =
-        parser.read_as().expect("error decoding backup info");
+pub fn collect_kbi_objects(
+    kbi_path: String,
+    repo_path: String,
+) -> anyhow::Result<Vec<(PathBuf, String)>> {
+    let backup_info = load_kbi(&kbi_path)?;
     let (send, recv) = crossbeam::channel::bounded(1024);
     thread::spawn(move || {
         traverse_all(
@@ -61,20 +73,92 @@ pub fn collect_kbi_objects(kbi_path: String, repo_path: String) -> Vec<(PathBuf,
             &mut |pb, s| send.send((pb, s)).expect("error sending object"),
         );
     });
-    recv.iter().collect()
+    Ok(recv.iter().collect())
 }
 
-fn traverse_all<T: FnMut(PathBuf, String) -> ()>(
+fn traverse_all<T: FnMut(PathBuf, String)>(
     root: &PathBuf,
     coll: &ObjectCollection2,
     callback: &mut T,
 ) {
     let elements: &HashMap<String, ObjectElement> = coll.elements.as_ref();
-    elements.iter().for_each(|(k, v)| {
+    elements.values().for_each(|v| {
         let file_name = v.identifier.to_string();
         callback(root.join(&file_name), file_name);
     });
-    coll.sub_collections.as_ref().iter().for_each(|(k, v)| {
-        traverse_all(&root, v, callback);
+    coll.sub_collections.as_ref().values().for_each(|v| {
+        traverse_all(root, v, callback);
     })
 }
+
+pub fn load_kbi(kbi_path: &str) -> anyhow::Result<java_objects::SavedIncBackupV1> {
+    let file = File::open(kbi_path)?;
+    let mut parser = jaded::Parser::new(file)?;
+    Ok(parser.read_as()?)
+}
+
+/// Visit `coll` and all its sub collections recursively, in pre-order.
+/// `dir` is the path of `coll`, sub collections are joined with their names.
+pub fn traverse_collections<'a, T: FnMut(&Path, &'a ObjectCollection2)>(
+    dir: &Path,
+    coll: &'a ObjectCollection2,
+    callback: &mut T,
+) {
+    callback(dir, coll);
+    coll.sub_collections.as_ref().values().for_each(|v| {
+        traverse_collections(&dir.join(&v.name), v, callback);
+    })
+}
+
+/// All files in the backup, keyed by their path starting with the name of the root collection.
+pub fn list_files(root: &ObjectCollection2) -> BTreeMap<PathBuf, &ObjectElement> {
+    let mut files = BTreeMap::new();
+    traverse_collections(Path::new(&root.name), root, &mut |dir, coll| {
+        for elem in coll.elements.as_ref().values() {
+            files.insert(dir.join(&elem.name), elem);
+        }
+    });
+    files
+}
+
+pub enum Resolved<'a> {
+    File(&'a ObjectElement),
+    Collection(&'a ObjectCollection2),
+}
+
+/// Resolve a `/` separated path inside the backup, starting with the name of the root collection.
+pub fn resolve<'a>(root: &'a ObjectCollection2, path: &str) -> Option<Resolved<'a>> {
+    let mut components = path.split('/');
+    if components.next()? != root.name {
+        return None;
+    }
+    let mut coll = root;
+    let mut components = components.peekable();
+    while let Some(name) = components.next() {
+        if components.peek().is_none()
+            && let Some(elem) = coll.elements.as_ref().get(name)
+        {
+            return Some(Resolved::File(elem));
+        }
+        coll = coll.sub_collections.as_ref().get(name)?;
+    }
+    Some(Resolved::Collection(coll))
+}
+
+/// Write a .kbi file, using class descriptors in `descs`.
+/// The file is written to a temporary file first, then renamed to `kbi_path`.
+pub fn write_kbi(
+    kbi_path: &Path,
+    backup_info: &java_objects::SavedIncBackupV1,
+    descs: &KbiClassDescs,
+) -> anyhow::Result<()> {
+    let mut tmp_path = kbi_path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let file = BufWriter::new(File::create(&tmp_path)?);
+    let mut writer = ObjectWriter::new(file)?;
+    backup_info.to_java(&mut writer, descs)?;
+    let file = writer.into_inner()?.into_inner()?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, kbi_path)?;
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use crate::java_objects::ObjectElement;
+use crate::kbi_verification::{load_kbi, traverse_collections};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+pub fn ls_kbi(
+    kbi_path: String,
+    prefix: Option<String>,
+    repo_path: Option<String>,
+    long: bool,
+    tree: bool,
+) {
+    let backup_info = match load_kbi(&kbi_path) {
+        Ok(v) => v,
+        Err(why) => {
+            tracing::error!("error decoding kbi file {}: {}", &kbi_path, why);
+            exit(1);
+        }
+    };
+    let root = &backup_info.object_collection2;
+    let prefix = PathBuf::from(prefix.unwrap_or_default().trim_matches('/'));
+
+    // directories have no element
+    let mut entries: Vec<(PathBuf, Option<&ObjectElement>)> = Vec::new();
+    traverse_collections(Path::new(&root.name), root, &mut |dir, coll| {
+        entries.push((dir.to_path_buf(), None));
+        for elem in coll.elements.as_ref().values() {
+            entries.push((dir.join(&elem.name), Some(elem)));
+        }
+    });
+    // PathBuf is ordered by components, so children always follow their parent directory
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (path, elem) in entries.iter() {
+        if !path.starts_with(&prefix) || (!tree && elem.is_none()) {
+            continue;
+        }
+        let mut line = String::new();
+        if long {
+            match elem {
+                Some(elem) => {
+                    let id = elem.identifier.to_string();
+                    let size = repo_path
+                        .as_ref()
+                        .and_then(|repo| fs::metadata(Path::new(repo).join(&id)).ok())
+                        .map(|m| m.len().to_string())
+                        .unwrap_or_else(|| "-".to_string());
+                    line.push_str(&format!("{} {:>12} ", id, size));
+                }
+                None => line.push_str(&format!("{:67} {:>12} ", "-", "-")),
+            }
+        }
+        if tree {
+            let depth = path.components().count() - 1;
+            line.push_str(&"  ".repeat(depth));
+            line.push_str(&path.file_name().unwrap_or_default().to_string_lossy());
+            if elem.is_none() {
+                line.push('/');
+            }
+        } else {
+            line.push_str(&path.to_string_lossy());
+        }
+        println!("{}", line);
+    }
+}
@@ -1,16 +1,56 @@
 use std::thread;
 
+use crate::backup::create_backup;
+use crate::check_refs::check_refs;
+use crate::diff::diff_kbi;
 use crate::dump_kbi::dump_kbi;
+use crate::export_zip::export_zip;
+use crate::extract::extract_kbi;
+use crate::gc::gc_objects;
+use crate::history::file_history;
+use crate::import_zip::import_zips;
 use crate::kbi_verification::verify_kbi;
+use crate::ls::ls_kbi;
+use crate::prune::prune_backups;
+use crate::repair::RepairOptions;
 use crate::repo_verification::verify_incremental_store;
+use crate::restore::restore_kbi;
+use crate::retention::RetentionPolicy;
+use crate::stats::repo_stats;
+use crate::top::top_paths;
+use crate::unarchive::{BackupSelection, unarchive_backups};
+use crate::zip_verification::verify_zips;
 use clap::{Parser, Subcommand};
 use signal_hook::{consts::SIGPIPE, iterator::Signals, low_level::exit};
 
 mod archive;
+mod archive_journal;
+mod atomic_file;
+mod backup;
+mod check_refs;
+mod diff;
 mod dump_kbi;
+mod export_zip;
+mod extract;
+mod gc;
+mod hash_algorithm;
+mod history;
+mod import_zip;
 mod java_objects;
+mod java_stream;
 mod kbi_verification;
+mod ls;
+mod move_file;
+mod prune;
+mod repair;
 mod repo_verification;
+mod restore;
+mod retention;
+mod stats;
+mod top;
+mod unarchive;
+mod verification_cache;
+mod zip_verification;
 
 #[derive(Parser)]
 struct CliArgs {
@@ -20,7 +60,12 @@ struct CliArgs {
 
 #[derive(Subcommand)]
 enum Commands {
-    #[command(about = "verify checksum of all incremental backup files")]
+    #[command(
+        about = "verify checksum of all incremental backup files",
+        long_about = "verify checksum of all incremental backup files; \
+            exit with 2 if corrupted objects are found, \
+            3 if some objects cannot be read or use an unsupported hash algorithm"
+    )]
     VerifyBackupRepo {
         #[arg(help = "path to the incremental backup directory")]
         path: String,
@@ -31,6 +76,36 @@ enum Commands {
             default_value = "0"
         )]
         threads: usize,
+        #[clap(long, action, help = "print the report in JSON format")]
+        json: bool,
+        #[clap(
+            long,
+            help = "skip objects verified within this duration and not modified since, e.g. `30d`"
+        )]
+        since: Option<String>,
+        #[clap(long, action, help = "verify all objects, ignoring --since")]
+        full: bool,
+        #[clap(
+            long,
+            help = "path to the verification cache file, defaults to a hidden file in the incremental backup directory"
+        )]
+        cache: Option<String>,
+        #[clap(
+            long,
+            action,
+            help = "move corrupted objects into quarantine and restore good copies from --source"
+        )]
+        repair: bool,
+        #[clap(
+            long,
+            help = "quarantine directory for --repair, defaults to `.quarantine` in the incremental backup directory"
+        )]
+        quarantine: Option<String>,
+        #[clap(
+            long = "source",
+            help = "directory with copies of objects for --repair, e.g. the archived incremental backup directory or a mirror; may be repeated"
+        )]
+        sources: Vec<String>,
     },
     #[command(about = "dump kbi info in JSON format")]
     DumpKbi {
@@ -40,15 +115,294 @@ enum Commands {
         #[arg(help = "pretty print")]
         pretty: bool,
     },
-    #[command(about = "verify checksum of all files in the .kbi file")]
+    #[command(
+        about = "verify checksum of all files in the .kbi file",
+        long_about = "verify checksum of all files in the .kbi file; \
+            exit with 2 if corrupted or missing objects are found, \
+            3 if some objects cannot be read or use an unsupported hash algorithm"
+    )]
     VerifyKbi {
         #[arg(help = "path to the incremental backup directory")]
         repo_path: String,
-        #[arg(help = "path to the .kbi file", trailing_var_arg = true)]
+        #[arg(help = "path to the .kbi file")]
         kbi_path: Vec<String>,
+        #[clap(long, action, help = "print the report in JSON format")]
+        json: bool,
+        #[clap(
+            long,
+            action,
+            help = "move corrupted objects into quarantine and restore good copies from --source"
+        )]
+        repair: bool,
+        #[clap(
+            long,
+            help = "quarantine directory for --repair, defaults to `.quarantine` in the incremental backup directory"
+        )]
+        quarantine: Option<String>,
+        #[clap(
+            long = "source",
+            help = "directory with copies of objects for --repair, e.g. the archived incremental backup directory or a mirror; may be repeated"
+        )]
+        sources: Vec<String>,
     },
     #[command(about = "archive old incremental backups")]
     Archive {
+        #[arg(help = "path to the incremental backup directory")]
+        kbi_repo: String,
+        #[arg(
+            required_unless_present_any = ["resume", "rollback"],
+            help = "path to the backups folder"
+        )]
+        backups: Option<String>,
+        #[arg(
+            required_unless_present_any = ["resume", "rollback"],
+            help = "path to the archived incremental backup directory"
+        )]
+        archive_kbi_repo: Option<String>,
+        #[arg(
+            required_unless_present_any = ["resume", "rollback"],
+            help = "path to the archived backups folder"
+        )]
+        archive_backups: Option<String>,
+        #[arg(help = "maximum live time of files before being archived, same as --keep-within")]
+        ttl: Option<String>,
+        #[command(flatten)]
+        policy: RetentionPolicy,
+        #[clap(
+            long,
+            short,
+            help = "do not move any file, just print those actions",
+            default_value = "false"
+        )]
+        dry_run: bool,
+        #[clap(long, action, help = "only complete an interrupted archive")]
+        resume: bool,
+        #[clap(
+            long,
+            action,
+            conflicts_with = "resume",
+            help = "move files of an interrupted archive back"
+        )]
+        rollback: bool,
+    },
+    #[command(about = "restore files in a .kbi backup into a directory")]
+    Restore {
+        #[arg(help = "path to the incremental backup directory")]
+        repo_path: String,
+        #[arg(help = "path to the .kbi file")]
+        kbi_path: String,
+        #[arg(help = "path to the target directory")]
+        target: String,
+        #[clap(
+            long,
+            short,
+            help = "restore even if the target directory is not empty",
+            default_value = "false"
+        )]
+        force: bool,
+    },
+    #[command(about = "extract a file or a subtree from a .kbi backup")]
+    Extract {
+        #[arg(help = "path to the incremental backup directory")]
+        repo_path: String,
+        #[arg(help = "path to the .kbi file")]
+        kbi_path: String,
+        #[arg(help = "path inside the backup, e.g. `world/region/r.0.0.mca` or `world/DIM-1/**`")]
+        path: String,
+        #[arg(help = "output file or directory")]
+        output: String,
+    },
+    #[command(about = "list files stored in a .kbi backup")]
+    Ls {
+        #[arg(help = "path to the .kbi file")]
+        kbi_path: String,
+        #[arg(help = "only list paths under this prefix, e.g. `world/region`")]
+        prefix: Option<String>,
+        #[clap(
+            long,
+            help = "path to the incremental backup directory, used to show object sizes"
+        )]
+        repo: Option<String>,
+        #[clap(long, short, action, help = "show object hash and size")]
+        long: bool,
+        #[clap(long, short, action, help = "show an indented tree view")]
+        tree: bool,
+    },
+    #[command(about = "compare files in two .kbi backups")]
+    Diff {
+        #[arg(help = "path to the older .kbi file")]
+        old_kbi: String,
+        #[arg(help = "path to the newer .kbi file")]
+        new_kbi: String,
+        #[clap(
+            long,
+            help = "path to the incremental backup directory, used to show size deltas"
+        )]
+        repo: Option<String>,
+        #[clap(long, action, help = "print result in JSON format")]
+        json: bool,
+    },
+    #[command(about = "show versions of a file across all .kbi backups")]
+    History {
+        #[arg(help = "path to the backups folder")]
+        backups: String,
+        #[arg(help = "path inside the backup, e.g. `world/level.dat`")]
+        path: String,
+    },
+    #[command(about = "create an incremental backup of a directory")]
+    Backup {
+        #[arg(help = "path to the world directory to back up")]
+        world: String,
+        #[arg(help = "path to the incremental backup directory")]
+        kbi_repo: String,
+        #[arg(help = "path to the backups folder")]
+        backups: String,
+        #[arg(help = "name of the backup")]
+        name: String,
+        #[clap(
+            long,
+            help = "copy Java class descriptors from this .kbi file; defaults to the latest .kbi in the backups folder"
+        )]
+        template: Option<String>,
+    },
+    #[command(about = "convert a .kbi backup into a full backup .zip file")]
+    ExportZip {
+        #[arg(help = "path to the incremental backup directory")]
+        repo_path: String,
+        #[arg(help = "path to the .kbi file")]
+        kbi_path: String,
+        #[arg(help = "path to the output .zip file, `-` for stdout")]
+        output: String,
+    },
+    #[command(about = "import full backup .zip files into the incremental backup directory")]
+    ImportZip {
+        #[arg(help = "path to the incremental backup directory")]
+        kbi_repo: String,
+        #[arg(help = "path to the backups folder, where .kbi files are written")]
+        backups: String,
+        #[arg(help = "path to the .zip files", required = true)]
+        zip_paths: Vec<String>,
+        #[clap(
+            long,
+            help = "name of the world directory, if files in the zip are not inside a single directory"
+        )]
+        root_name: Option<String>,
+        #[clap(
+            long,
+            help = "copy Java class descriptors from this .kbi file; defaults to the latest .kbi in the backups folder"
+        )]
+        template: Option<String>,
+        #[clap(
+            long,
+            action,
+            conflicts_with = "archive_to",
+            help = "delete the .zip file after it is imported and verified"
+        )]
+        delete: bool,
+        #[clap(
+            long,
+            help = "move the .zip file to this directory after it is imported and verified"
+        )]
+        archive_to: Option<String>,
+    },
+    #[command(about = "verify CRC-32 of all entries in full backup .zip files")]
+    VerifyZip {
+        #[arg(
+            help = "path to the .zip files, or backups folders containing them",
+            required = true
+        )]
+        paths: Vec<String>,
+        #[clap(
+            long,
+            short,
+            help = "how many threads to use; if HDD, set it to 1",
+            default_value = "0"
+        )]
+        threads: usize,
+    },
+    #[command(about = "remove objects not referenced by any .kbi backup")]
+    Gc {
+        #[arg(help = "path to the incremental backup directory")]
+        kbi_repo: String,
+        #[arg(help = "path to the backups folder")]
+        backups: String,
+        #[clap(
+            long,
+            help = "move orphan objects to this directory instead of deleting them"
+        )]
+        quarantine: Option<String>,
+        #[clap(
+            long,
+            help = "only remove orphan objects older than this, e.g. `6h`; \
+                a running backup writes its objects before its .kbi file",
+            default_value = "6h"
+        )]
+        min_age: String,
+        #[clap(
+            long,
+            short,
+            help = "do not remove any file, just print orphan objects",
+            default_value = "false"
+        )]
+        dry_run: bool,
+    },
+    #[command(
+        about = "report objects used by .kbi backups but missing in the incremental backup directory",
+        long_about = "report objects used by .kbi backups but missing in the incremental backup directory; \
+            exit with 2 if some backups are partially damaged, 4 if some backups are unrestorable, \
+            3 if backups or objects cannot be listed"
+    )]
+    CheckRefs {
+        #[arg(help = "path to the incremental backup directory")]
+        kbi_repo: String,
+        #[arg(help = "path to the backups folder")]
+        backups: String,
+    },
+    #[command(about = "show repo size, cost of each backup and deduplication ratio")]
+    Stats {
+        #[arg(help = "path to the incremental backup directory")]
+        kbi_repo: String,
+        #[arg(help = "path to the backups folder")]
+        backups: String,
+        #[clap(long, action, help = "print in JSON format")]
+        json: bool,
+    },
+    #[command(about = "list the largest and most often changed files in backups")]
+    Top {
+        #[arg(help = "path to the incremental backup directory")]
+        kbi_repo: String,
+        #[arg(help = "path to the backups folder")]
+        backups: String,
+        #[clap(
+            long,
+            help = "first backup, as a backup file name or a time like `2024-03-05` or `2024-03-05_12-00-00`"
+        )]
+        from: Option<String>,
+        #[clap(
+            long,
+            help = "last backup, as a backup file name or a time like `2024-03-05` or `2024-03-05_12-00-00`"
+        )]
+        to: Option<String>,
+        #[clap(short, help = "number of files to show", default_value = "20")]
+        n: usize,
+    },
+    #[command(
+        about = "permanently delete old backups and objects only they reference",
+        long_about = "permanently delete backups not kept by the retention policy and objects only they reference; \
+            without --yes, only print what would be deleted"
+    )]
+    Prune {
+        #[arg(help = "path to the incremental backup directory")]
+        kbi_repo: String,
+        #[arg(help = "path to the backups folder")]
+        backups: String,
+        #[command(flatten)]
+        policy: RetentionPolicy,
+        #[clap(long, action, help = "really delete the files")]
+        yes: bool,
+    },
+    #[command(about = "move archived backups and their objects back")]
+    Unarchive {
         #[arg(help = "path to the incremental backup directory")]
         kbi_repo: String,
         #[arg(help = "path to the backups folder")]
@@ -57,8 +411,8 @@ enum Commands {
         archive_kbi_repo: String,
         #[arg(help = "path to the archived backups folder")]
         archive_backups: String,
-        #[arg(help = "maximum live time of files before being archived")]
-        ttl: String,
+        #[command(flatten)]
+        selection: BackupSelection,
         #[clap(
             long,
             short,
@@ -74,15 +428,26 @@ fn main() {
 
     let mut sigpipe = Signals::new([SIGPIPE]).expect("error creating signal handler");
     thread::spawn(move || {
-        for _ in sigpipe.forever() {
+        if sigpipe.forever().next().is_some() {
             exit(0);
         }
     });
 
     let cli = CliArgs::parse();
     match cli.command {
-        Commands::VerifyBackupRepo { path, threads } => {
-            verify_incremental_store(path, threads);
+        Commands::VerifyBackupRepo {
+            path,
+            threads,
+            json,
+            since,
+            full,
+            cache,
+            repair,
+            quarantine,
+            sources,
+        } => {
+            let repair = repair.then(|| RepairOptions::new(&path, quarantine, sources));
+            verify_incremental_store(path, threads, json, since, full, cache, repair);
         }
         Commands::DumpKbi { path, pretty } => {
             dump_kbi(path, pretty);
@@ -90,8 +455,13 @@ fn main() {
         Commands::VerifyKbi {
             kbi_path,
             repo_path,
+            json,
+            repair,
+            quarantine,
+            sources,
         } => {
-            verify_kbi(kbi_path, repo_path);
+            let repair = repair.then(|| RepairOptions::new(&repo_path, quarantine, sources));
+            verify_kbi(kbi_path, repo_path, json, repair);
         }
         Commands::Archive {
             kbi_repo,
@@ -99,14 +469,156 @@ fn main() {
             archive_kbi_repo,
             archive_backups,
             ttl,
+            mut policy,
             dry_run,
+            resume,
+            rollback,
         } => {
+            if resume || rollback {
+                archive::resume_archive(
+                    kbi_repo,
+                    backups,
+                    archive_kbi_repo,
+                    archive_backups,
+                    rollback,
+                );
+                return;
+            }
+            if ttl.is_some() {
+                policy.keep_within = ttl;
+            }
+            let (Some(backups), Some(archive_kbi_repo), Some(archive_backups)) =
+                (backups, archive_kbi_repo, archive_backups)
+            else {
+                unreachable!("required unless resuming");
+            };
             archive::archive_backups(
                 kbi_repo,
                 backups,
                 archive_kbi_repo,
                 archive_backups,
-                ttl,
+                policy,
+                dry_run,
+            );
+        }
+        Commands::Restore {
+            repo_path,
+            kbi_path,
+            target,
+            force,
+        } => {
+            restore_kbi(kbi_path, repo_path, target, force);
+        }
+        Commands::Extract {
+            repo_path,
+            kbi_path,
+            path,
+            output,
+        } => {
+            extract_kbi(kbi_path, repo_path, path, output);
+        }
+        Commands::Ls {
+            kbi_path,
+            prefix,
+            repo,
+            long,
+            tree,
+        } => {
+            ls_kbi(kbi_path, prefix, repo, long, tree);
+        }
+        Commands::Diff {
+            old_kbi,
+            new_kbi,
+            repo,
+            json,
+        } => {
+            diff_kbi(old_kbi, new_kbi, repo, json);
+        }
+        Commands::History { backups, path } => {
+            file_history(backups, path);
+        }
+        Commands::Backup {
+            world,
+            kbi_repo,
+            backups,
+            name,
+            template,
+        } => {
+            create_backup(world, kbi_repo, backups, name, template);
+        }
+        Commands::ExportZip {
+            repo_path,
+            kbi_path,
+            output,
+        } => {
+            export_zip(kbi_path, repo_path, output);
+        }
+        Commands::ImportZip {
+            kbi_repo,
+            backups,
+            zip_paths,
+            root_name,
+            template,
+            delete,
+            archive_to,
+        } => {
+            import_zips(
+                kbi_repo, backups, zip_paths, root_name, template, delete, archive_to,
+            );
+        }
+        Commands::VerifyZip { paths, threads } => {
+            verify_zips(paths, threads);
+        }
+        Commands::Gc {
+            kbi_repo,
+            backups,
+            quarantine,
+            min_age,
+            dry_run,
+        } => {
+            gc_objects(kbi_repo, backups, quarantine, min_age, dry_run);
+        }
+        Commands::CheckRefs { kbi_repo, backups } => {
+            check_refs(kbi_repo, backups);
+        }
+        Commands::Stats {
+            kbi_repo,
+            backups,
+            json,
+        } => {
+            repo_stats(kbi_repo, backups, json);
+        }
+        Commands::Top {
+            kbi_repo,
+            backups,
+            from,
+            to,
+            n,
+        } => {
+            top_paths(kbi_repo, backups, from, to, n);
+        }
+        Commands::Prune {
+            kbi_repo,
+            backups,
+            policy,
+            yes,
+        } => {
+            prune_backups(kbi_repo, backups, policy, yes);
+        }
+        Commands::Unarchive {
+            kbi_repo,
+            backups,
+            archive_kbi_repo,
+            archive_backups,
+            selection,
+            dry_run,
+        } => {
+            unarchive_backups(
+                kbi_repo,
+                backups,
+                archive_kbi_repo,
+                archive_backups,
+                selection,
                 dry_run,
             );
         }
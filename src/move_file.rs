@@ -0,0 +1,38 @@
+use crate::atomic_file::write_atomic;
+use crate::hash_algorithm::HashAlgorithm;
+use crate::repo_verification::hash_file;
+use anyhow::anyhow;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// Move a file with `rename`. If `dst` is on another filesystem, copy it,
+/// fsync and verify the copy, then unlink `src`.
+pub fn move_file(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    match fs::rename(src, dst) {
+        Ok(()) => Ok(()),
+        Err(why) if why.kind() == io::ErrorKind::CrossesDevices => copy_and_unlink(src, dst),
+        Err(why) => Err(why.into()),
+    }
+}
+
+fn copy_and_unlink(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    let alg = HashAlgorithm::DEFAULT;
+    let dir = dst.parent().unwrap_or(Path::new("."));
+    write_atomic(File::open(src)?, dir, alg, |tmp, expected_hash| {
+        // read back what actually reached the disk
+        let actual_hash = hash_file(alg, tmp.to_path_buf())?;
+        if actual_hash != expected_hash {
+            return Err(anyhow!(
+                "copy of {} is corrupted, expected: {}, actual: {}",
+                src.display(),
+                expected_hash,
+                actual_hash
+            ));
+        }
+        Ok(Some(dst.to_path_buf()))
+    })?;
+    fs::remove_file(src)?;
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use crate::archive::{new_backup_objects, plan_expiry};
+use crate::archive_journal::ArchiveJournal;
+use crate::retention::RetentionPolicy;
+use indicatif::HumanBytes;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::process::exit;
+
+/// Permanently delete backups expired by the policy and objects only they reference.
+/// Without `confirm`, only print what would be deleted.
+pub fn prune_backups(incr_repo: String, backups: String, policy: RetentionPolicy, confirm: bool) {
+    if let Err(why) = ArchiveJournal::ensure_none_pending(&incr_repo) {
+        tracing::error!("{}", why);
+        exit(1);
+    }
+    let plan = plan_expiry(&incr_repo, &backups, &policy, true);
+    let total_size = |dir: &str, names: &[String]| -> u64 {
+        names
+            .iter()
+            .map(|name| {
+                fs::metadata(Path::new(dir).join(name))
+                    .map(|m| m.len())
+                    .unwrap_or(0)
+            })
+            .sum()
+    };
+    let backup_bytes = total_size(&backups, &plan.backups);
+    let object_bytes = total_size(&incr_repo, &plan.objects);
+    println!(
+        "{} {} backups ({}) and {} objects ({}), {} freed in total",
+        if confirm { "deleting" } else { "would delete" },
+        plan.backups.len(),
+        HumanBytes(backup_bytes),
+        plan.objects.len(),
+        HumanBytes(object_bytes),
+        HumanBytes(backup_bytes + object_bytes)
+    );
+    if !confirm {
+        println!("run again with --yes to delete them");
+        return;
+    }
+
+    // backups go first, so no remaining backup references a deleted object
+    for backup in plan.backups.iter() {
+        if let Err(why) = fs::remove_file(Path::new(&backups).join(backup)) {
+            tracing::error!("error deleting backup file {}: {}, abort", backup, why);
+            exit(1);
+        }
+        tracing::info!("deleted: {}", backup);
+    }
+    // backups created meanwhile may use objects planned to delete
+    let mut known: HashSet<&String> = plan.active_backups.iter().collect();
+    known.extend(plan.backups.iter());
+    let objects: Vec<&String> = match new_backup_objects(&incr_repo, &backups, &known) {
+        Ok(used) => plan
+            .objects
+            .iter()
+            .filter(|obj| !used.contains(*obj))
+            .collect(),
+        Err(why) => {
+            tracing::warn!("{}, objects are not deleted", why);
+            Vec::new()
+        }
+    };
+    let mut failed = 0usize;
+    for obj in objects {
+        match fs::remove_file(Path::new(&incr_repo).join(obj)) {
+            Ok(()) => tracing::debug!("deleted: {}", obj),
+            Err(why) => {
+                tracing::error!("error deleting incremental object file {}: {}", obj, why);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        tracing::error!("failed to delete {} objects", failed);
+        exit(1);
+    }
+}
@@ -0,0 +1,107 @@
+use crate::atomic_file::write_atomic;
+use crate::hash_algorithm::HashAlgorithm;
+use crate::move_file::move_file;
+use crate::repo_verification::VerifyReport;
+use serde::Serialize;
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Default quarantine directory inside the incremental repo
+const QUARANTINE_DIR_NAME: &str = ".quarantine";
+
+pub struct RepairOptions {
+    pub quarantine: PathBuf,
+    /// Directories holding copies of objects, e.g. the archived incremental repo or a mirror
+    pub sources: Vec<PathBuf>,
+}
+
+impl RepairOptions {
+    pub fn new(incr_repo: &str, quarantine: Option<String>, sources: Vec<String>) -> Self {
+        RepairOptions {
+            quarantine: match quarantine {
+                Some(v) => PathBuf::from(v),
+                None => Path::new(incr_repo).join(QUARANTINE_DIR_NAME),
+            },
+            sources: sources.into_iter().map(PathBuf::from).collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Repair {
+    pub object: String,
+    /// `None` if no good copy was found, the object stays in quarantine
+    pub source: Option<String>,
+}
+
+/// Move objects with mismatched hash into quarantine and restore good copies from sources.
+pub fn repair_objects(incr_repo: &Path, report: &mut VerifyReport, opts: &RepairOptions) {
+    if report.mismatch.is_empty() {
+        return;
+    }
+    if let Err(why) = fs::create_dir_all(&opts.quarantine) {
+        tracing::error!(
+            "error creating quarantine directory {}: {}",
+            opts.quarantine.display(),
+            why
+        );
+        return;
+    }
+    for m in report.mismatch.iter() {
+        let obj_path = incr_repo.join(&m.object);
+        if let Err(why) = quarantine(&obj_path, &opts.quarantine, &m.object) {
+            tracing::error!("error quarantining {}: {}", m.object, why);
+            continue;
+        }
+        let source = opts.sources.iter().find(|src| {
+            match restore_copy(src, incr_repo, &m.object, &m.expected) {
+                Ok(restored) => restored,
+                Err(why) => {
+                    tracing::warn!("error copying {} from {}: {}", m.object, src.display(), why);
+                    false
+                }
+            }
+        });
+        report.repaired.push(Repair {
+            object: m.object.clone(),
+            source: source.map(|p| p.to_string_lossy().into_owned()),
+        });
+    }
+}
+
+fn quarantine(obj_path: &Path, quarantine: &Path, object: &str) -> anyhow::Result<()> {
+    let mut dst = quarantine.join(object);
+    if dst.exists() {
+        // keep earlier quarantined copies
+        let t = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        dst = quarantine.join(format!("{}.{}", object, t));
+    }
+    move_file(obj_path, &dst)
+}
+
+/// Copy `object` from `src` into the repo if its hash matches. Returns false if no good copy exists.
+fn restore_copy(
+    src: &Path,
+    incr_repo: &Path,
+    object: &str,
+    expected: &str,
+) -> anyhow::Result<bool> {
+    let src_path = src.join(object);
+    if !src_path.is_file() {
+        return Ok(false);
+    }
+    let (alg, _) = HashAlgorithm::from_object_name(object)?;
+    let (hash, _) = write_atomic(File::open(&src_path)?, incr_repo, alg, |_, hash| {
+        Ok((hash == expected).then(|| incr_repo.join(object)))
+    })?;
+    if hash != expected {
+        tracing::warn!("copy of {} in {} is corrupted too", object, src.display());
+        return Ok(false);
+    }
+    Ok(true)
+}
@@ -1,86 +1,430 @@
+use crate::hash_algorithm::{HashAlgorithm, Hasher};
+use crate::repair::{Repair, RepairOptions, repair_objects};
+use crate::verification_cache::{CACHE_FILE_NAME, CacheEntry, VerificationCache};
 use crossbeam::channel::Receiver;
-use sha2::{Digest, Sha256};
-use std::fs::{File};
-use std::path::PathBuf;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
 use std::{fs, io, thread};
 
-pub fn verify_incremental_store(path: String, mut threads: usize) {
-    let n = match fs::read_dir(&path) {
-        Ok(p) => p.count(),
-        Err(why) => {
-            tracing::error!("error reading directory {}", why);
+/// Exit code when some objects are corrupted or missing
+pub const EXIT_CORRUPTED: i32 = 2;
+/// Exit code when some objects cannot be read or verified, but none are known to be corrupted
+pub const EXIT_IO_ERROR: i32 = 3;
+
+#[derive(Serialize, Default)]
+pub struct VerifyReport {
+    pub ok: usize,
+    #[serde(skip)]
+    pub ok_objects: Vec<String>,
+    /// Objects not hashed because they were verified recently
+    pub skipped: usize,
+    pub mismatch: Vec<HashMismatch>,
+    /// Objects referenced by a backup but not in the repo
+    pub missing: Vec<String>,
+    pub unreadable: Vec<ReadError>,
+    /// Objects of a hash algorithm this tool cannot verify
+    pub unsupported: Vec<ReadError>,
+    pub repaired: Vec<Repair>,
+}
+
+#[derive(Serialize)]
+pub struct HashMismatch {
+    pub object: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+#[derive(Serialize)]
+pub struct ReadError {
+    pub path: String,
+    pub error: String,
+}
+
+impl VerifyReport {
+    fn merge(&mut self, other: VerifyReport) {
+        self.ok += other.ok;
+        self.ok_objects.extend(other.ok_objects);
+        self.skipped += other.skipped;
+        self.mismatch.extend(other.mismatch);
+        self.missing.extend(other.missing);
+        self.unreadable.extend(other.unreadable);
+        self.unsupported.extend(other.unsupported);
+        self.repaired.extend(other.repaired);
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        let unrepaired =
+            self.mismatch.len() - self.repaired.iter().filter(|r| r.source.is_some()).count();
+        if unrepaired > 0 || !self.missing.is_empty() {
+            EXIT_CORRUPTED
+        } else if !self.unreadable.is_empty() || !self.unsupported.is_empty() {
+            EXIT_IO_ERROR
+        } else {
+            0
+        }
+    }
+
+    /// Print the report and exit with `exit_code`.
+    pub fn finish(mut self, json: bool) -> ! {
+        self.mismatch.sort_by(|a, b| a.object.cmp(&b.object));
+        self.missing.sort();
+        self.unreadable.sort_by(|a, b| a.path.cmp(&b.path));
+        self.unsupported.sort_by(|a, b| a.path.cmp(&b.path));
+        if json {
+            if let Err(why) = serde_json::to_writer(io::stdout(), &self)
+                && !why.is_io()
+            {
+                tracing::error!("error encoding JSON: {}", why);
+            }
+            println!();
+        } else {
+            for m in self.mismatch.iter() {
+                println!(
+                    "file hash mismatch: {}, expected: {}, actual: {}",
+                    m.object, m.expected, m.actual
+                );
+            }
+            for obj in self.missing.iter() {
+                println!("missing: {}", obj);
+            }
+            for e in self.unreadable.iter() {
+                println!("unreadable: {}: {}", e.path, e.error);
+            }
+            for e in self.unsupported.iter() {
+                println!("unsupported: {}: {}", e.path, e.error);
+            }
+            for r in self.repaired.iter() {
+                match &r.source {
+                    Some(src) => println!("repaired: {} from {}", r.object, src),
+                    None => println!("quarantined, no good copy found: {}", r.object),
+                }
+            }
+            println!(
+                "verified {} objects: {} ok, {} mismatch, {} missing, {} unreadable, {} unsupported",
+                self.ok
+                    + self.mismatch.len()
+                    + self.missing.len()
+                    + self.unreadable.len()
+                    + self.unsupported.len(),
+                self.ok,
+                self.mismatch.len(),
+                self.missing.len(),
+                self.unreadable.len(),
+                self.unsupported.len()
+            );
+            if self.skipped > 0 {
+                println!("skipped {} recently verified objects", self.skipped);
+            }
+        }
+        exit(self.exit_code())
+    }
+}
+
+/// Objects and bytes hashed so far, drawn on stderr if it is a terminal.
+#[derive(Clone)]
+pub struct VerifyProgress {
+    bar: ProgressBar,
+    done: Arc<AtomicU64>,
+    total: Arc<AtomicU64>,
+}
+
+impl VerifyProgress {
+    pub fn new() -> Self {
+        let bar = if io::stderr().is_terminal() {
+            ProgressBar::new(0)
+        } else {
+            ProgressBar::hidden()
+        };
+        bar.set_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] [{wide_bar}] {bytes}/{total_bytes} \
+                 ({binary_bytes_per_sec}, ETA {eta}) {msg}",
+            )
+            .expect("invalid progress bar template")
+            .progress_chars("=> "),
+        );
+        VerifyProgress {
+            bar,
+            done: Arc::new(AtomicU64::new(0)),
+            total: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Add an object of `size` bytes to be verified.
+    pub fn add_object(&self, size: u64) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+        self.bar.inc_length(size);
+    }
+
+    fn object_done(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        self.bar.set_message(format!(
+            "{}/{} objects",
+            done,
+            self.total.load(Ordering::Relaxed)
+        ));
+    }
+}
+
+/// Verify all objects in the incremental repo.
+/// With `since`, objects verified within that duration according to the cache are skipped,
+/// unless `full` is set.
+pub fn verify_incremental_store(
+    path: String,
+    threads: usize,
+    json: bool,
+    since: Option<String>,
+    full: bool,
+    cache_path: Option<String>,
+    repair: Option<RepairOptions>,
+) {
+    let not_before = match since.filter(|_| !full).map(duration_str::parse) {
+        Some(Ok(dur)) => Some(SystemTime::now() - dur),
+        Some(Err(why)) => {
+            tracing::error!("cannot parse duration string: {}", why);
             exit(1);
         }
+        None => None,
+    };
+    let cache_path = match cache_path {
+        Some(v) => PathBuf::from(v),
+        None => Path::new(&path).join(CACHE_FILE_NAME),
+    };
+    let mut cache = match VerificationCache::load(&cache_path) {
+        Ok(v) => v,
+        Err(why) => {
+            tracing::warn!(
+                "error loading verification cache {}: {}, verifying all objects",
+                cache_path.display(),
+                why
+            );
+            VerificationCache::default()
+        }
     };
-    let paths = match fs::read_dir(&path) {
+    let entries = match fs::read_dir(&path) {
         Ok(p) => p,
         Err(why) => {
             tracing::error!("error reading directory {}", why);
             exit(1);
         }
     };
+
+    let started = SystemTime::now();
+    let progress = VerifyProgress::new();
+    let mut skipped = 0;
+    let mut present = HashSet::new();
+    // metadata before hashing, recorded in the cache if the object is ok
+    let mut pending = HashMap::new();
+    let mut objects = Vec::new();
+    for p in entries.flatten() {
+        let file_name = p.file_name().into_string().expect("invalid file name");
+        if !is_repo_object(&file_name) {
+            continue;
+        }
+        present.insert(file_name.clone());
+        let entry = match p.metadata() {
+            Ok(m) => CacheEntry::from_metadata(&m, started),
+            Err(_) => CacheEntry {
+                size: 0,
+                mtime: 0,
+                verified: 0,
+            },
+        };
+        if let Some(t) = not_before
+            && cache.is_fresh(&file_name, &entry, t)
+        {
+            skipped += 1;
+            continue;
+        }
+        progress.add_object(entry.size);
+        pending.insert(file_name.clone(), entry);
+        objects.push((p.path(), file_name));
+    }
+
     let (send, recv) = crossbeam::channel::bounded(1024);
     thread::spawn(move || {
-        paths
-            .filter(|p| p.is_ok())
-            .map(|p| p.unwrap())
-            .for_each(|p| {
-                send.send((
-                    p.path(),
-                    p.file_name().into_string().expect("invalid file name"),
-                ))
-                .expect("error writing channel")
-            });
+        objects
+            .into_iter()
+            .for_each(|obj| send.send(obj).expect("error writing channel"));
     });
-    verify_files(threads, recv, n)
+    let mut report = verify_files(threads, recv, progress);
+    report.skipped = skipped;
+    if let Some(opts) = repair {
+        repair_objects(Path::new(&path), &mut report, &opts);
+    }
+
+    cache.retain(|obj| present.contains(obj));
+    for obj in report.ok_objects.iter() {
+        cache.insert(obj.clone(), pending[obj]);
+    }
+    for m in report.mismatch.iter() {
+        cache.remove(&m.object);
+    }
+    for e in report.unreadable.iter() {
+        cache.remove(&e.path);
+    }
+    for r in report.repaired.iter().filter(|r| r.source.is_some()) {
+        if let Ok(m) = fs::metadata(Path::new(&path).join(&r.object)) {
+            cache.insert(r.object.clone(), CacheEntry::from_metadata(&m, started));
+        }
+    }
+    if let Err(why) = cache.save(&cache_path) {
+        tracing::error!(
+            "error saving verification cache {}: {}",
+            cache_path.display(),
+            why
+        );
+    }
+    report.finish(json)
 }
 
-pub fn verify_files(mut threads: usize, recv: Receiver<(PathBuf, String)>, n: usize) {
+pub fn verify_files(
+    mut threads: usize,
+    recv: Receiver<(PathBuf, String)>,
+    progress: VerifyProgress,
+) -> VerifyReport {
     let mut workers = Vec::new();
-    // let prog_bar = ProgressBar::new(n as u64);
-    if threads <= 0 {
+    if threads == 0 {
         threads = num_cpus::get();
     }
     for _ in 0..threads {
-        // let prog_bar = prog_bar.clone();
+        let progress = progress.clone();
         let recv = recv.clone();
         workers.push(thread::spawn(move || {
+            let mut report = VerifyReport::default();
             recv.iter().for_each(|(path, file_name)| {
-                const PREFIX: &str = "S2-";
-                if !file_name.starts_with(PREFIX) {
-                    tracing::error!("unsupported hash algorithm: {}", file_name);
-                    return;
-                }
-                let expected_hash = &file_name[PREFIX.len()..];
-                let actual_hash = match hash_file(path) {
-                    Ok(h) => h,
-                    Err(why) => {
-                        tracing::error!("error hashing file {}: {}", file_name, why);
-                        return;
-                    }
-                };
-                if expected_hash != actual_hash {
-                    println!(
-                        "file hash mismatch: {}, expected: {}, actual: {}",
-                        file_name, expected_hash, actual_hash
-                    );
-                } else {
-                    tracing::debug!("checksum OK: {}", file_name);
-                }
-            })
-            // .for_each(|()| prog_bar.inc(1));
+                verify_object(&mut report, &progress, path, file_name);
+                progress.object_done();
+            });
+            report
         }));
     }
 
+    let mut report = VerifyReport::default();
     for w in workers {
-        w.join().expect("worker thread panicked");
+        report.merge(w.join().expect("worker thread panicked"));
     }
+    progress.bar.finish_and_clear();
+    report
 }
 
-fn hash_file(path: PathBuf) -> anyhow::Result<String> {
-    let mut sha256 = Sha256::new();
-    let mut file = File::open(path)?;
-    io::copy(&mut file, &mut sha256)?;
-    Ok(hex::encode_upper(sha256.finalize().to_vec()))
+fn verify_object(
+    report: &mut VerifyReport,
+    progress: &VerifyProgress,
+    path: PathBuf,
+    file_name: String,
+) {
+    let (alg, expected_hash) = match HashAlgorithm::from_object_name(&file_name) {
+        Ok(v) => v,
+        Err(why) => {
+            tracing::debug!("unsupported hash algorithm: {}", why);
+            report.unsupported.push(ReadError {
+                path: file_name.clone(),
+                error: why.to_string(),
+            });
+            return;
+        }
+    };
+    let actual_hash =
+        match File::open(&path).and_then(|f| hash_reader(alg, progress.bar.wrap_read(f))) {
+            Ok(h) => h,
+            Err(why) if why.kind() == io::ErrorKind::NotFound => {
+                tracing::debug!("missing object: {}", file_name);
+                report.missing.push(file_name);
+                return;
+            }
+            Err(why) => {
+                tracing::debug!("error hashing file {}: {}", file_name, why);
+                report.unreadable.push(ReadError {
+                    path: file_name,
+                    error: why.to_string(),
+                });
+                return;
+            }
+        };
+    if expected_hash != actual_hash {
+        tracing::debug!("file hash mismatch: {}", file_name);
+        report.mismatch.push(HashMismatch {
+            expected: expected_hash.to_string(),
+            object: file_name,
+            actual: actual_hash,
+        });
+    } else {
+        tracing::debug!("checksum OK: {}", file_name);
+        report.ok += 1;
+        report.ok_objects.push(file_name);
+    }
+}
+
+pub fn hash_file(alg: HashAlgorithm, path: PathBuf) -> anyhow::Result<String> {
+    Ok(hash_reader(alg, File::open(path)?)?)
+}
+
+pub fn hash_reader<R: Read>(alg: HashAlgorithm, mut r: R) -> io::Result<String> {
+    let mut hasher = alg.hasher();
+    io::copy(&mut r, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// Computes the hash of all data written through it.
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W, alg: HashAlgorithm) -> Self {
+        HashingWriter {
+            inner,
+            hasher: alg.hasher(),
+        }
+    }
+
+    /// Returns the inner writer and the hex encoded hash.
+    pub fn finish(self) -> (W, String) {
+        (self.inner, self.hasher.finalize())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hidden files in the repo are used by this tool, e.g. the verification cache, temp files
+/// and the archive journal, and are not objects.
+pub fn is_repo_object(file_name: &str) -> bool {
+    !file_name.starts_with('.')
+}
+
+/// File names of all objects in the incremental repo
+pub fn list_repo_objects(incr_repo: &str) -> anyhow::Result<Vec<String>> {
+    let mut result = Vec::new();
+    for entry in fs::read_dir(incr_repo)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        match entry.file_name().into_string() {
+            Ok(v) if !is_repo_object(&v) => continue,
+            Ok(v) => result.push(v),
+            Err(s) => return Err(anyhow::anyhow!("cannot decode OsString {:?}", s)),
+        }
+    }
+    Ok(result)
 }
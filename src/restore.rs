@@ -0,0 +1,130 @@
+use crate::atomic_file::write_atomic;
+use crate::hash_algorithm::HashAlgorithm;
+use crate::java_objects::{ObjectCollection2, SingleHashIdentifier};
+use crate::kbi_verification::{load_kbi, traverse_collections};
+use anyhow::anyhow;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+pub fn restore_kbi(kbi_path: String, repo_path: String, target: String, force: bool) {
+    let backup_info = match load_kbi(&kbi_path) {
+        Ok(v) => v,
+        Err(why) => {
+            tracing::error!("error decoding kbi file {}: {}", &kbi_path, why);
+            exit(1);
+        }
+    };
+    if let Err(why) = check_names(&backup_info.object_collection2) {
+        tracing::error!("refusing to restore {}: {}", &kbi_path, why);
+        exit(1);
+    }
+    let target = PathBuf::from(target);
+    match fs::read_dir(&target) {
+        Ok(mut entries) => {
+            if entries.next().is_some() && !force {
+                tracing::error!(
+                    "target directory is not empty: {}, use --force to overwrite",
+                    target.display()
+                );
+                exit(1);
+            }
+        }
+        Err(why) if why.kind() == ErrorKind::NotFound => {}
+        Err(why) => {
+            tracing::error!("error reading target directory: {}", why);
+            exit(1);
+        }
+    }
+
+    let repo = PathBuf::from(repo_path);
+    let mut restored = 0usize;
+    let mut missing = Vec::new();
+    let mut failed = Vec::new();
+    traverse_collections(
+        &target,
+        &backup_info.object_collection2,
+        &mut |dir, coll| {
+            if let Err(why) = fs::create_dir_all(dir) {
+                tracing::error!("error creating directory {}: {}", dir.display(), why);
+                exit(1);
+            }
+            for elem in coll.elements.as_ref().values() {
+                let dst = dir.join(&elem.name);
+                let obj_path = repo.join(elem.identifier.to_string());
+                if !obj_path.is_file() {
+                    missing.push((dst, elem.identifier.to_string()));
+                    continue;
+                }
+                match restore_object(&obj_path, &dst, &elem.identifier) {
+                    Ok(()) => {
+                        tracing::debug!("restored: {}", dst.display());
+                        restored += 1;
+                    }
+                    Err(why) => {
+                        tracing::error!("error restoring {}: {}", dst.display(), why);
+                        failed.push(dst);
+                    }
+                }
+            }
+        },
+    );
+
+    tracing::info!("restored {} files into {}", restored, target.display());
+    for (path, obj) in missing.iter() {
+        println!("missing object: {}, used by {}", obj, path.display());
+    }
+    for path in failed.iter() {
+        println!("failed to restore: {}", path.display());
+    }
+    if !missing.is_empty() || !failed.is_empty() {
+        tracing::error!(
+            "{} objects are missing, {} files failed to restore",
+            missing.len(),
+            failed.len()
+        );
+        exit(1);
+    }
+}
+
+/// Copy an object from the repo to `dst`, verifying its hash on the way.
+/// The data is written to a temporary file next to `dst` and only renamed into place if the hash matches.
+pub fn restore_object(
+    obj_path: &Path,
+    dst: &Path,
+    id: &SingleHashIdentifier,
+) -> anyhow::Result<()> {
+    let alg = HashAlgorithm::from_type(&id.typ)?;
+    let expected_hash = hex::encode_upper(&id.hash);
+    let dir = dst.parent().unwrap_or(Path::new("."));
+    let (actual_hash, _) = write_atomic(fs::File::open(obj_path)?, dir, alg, |_, hash| {
+        Ok((hash == expected_hash).then(|| dst.to_path_buf()))
+    })?;
+    if actual_hash != expected_hash {
+        return Err(anyhow!(
+            "file hash mismatch, expected: {}, actual: {}",
+            expected_hash,
+            actual_hash
+        ));
+    }
+    Ok(())
+}
+
+/// Reject collection and file names that would escape the directory they are restored into.
+pub fn check_names(coll: &ObjectCollection2) -> anyhow::Result<()> {
+    let is_unsafe =
+        |name: &str| name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']);
+    if is_unsafe(&coll.name) {
+        return Err(anyhow!("unsafe collection name: {:?}", coll.name));
+    }
+    for elem in coll.elements.as_ref().values() {
+        if is_unsafe(&elem.name) {
+            return Err(anyhow!("unsafe file name: {:?}", elem.name));
+        }
+    }
+    coll.sub_collections
+        .as_ref()
+        .values()
+        .try_for_each(check_names)
+}
@@ -0,0 +1,342 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Local};
+use clap::Args;
+use std::collections::HashMap;
+
+/// Which backups to keep. A backup is kept if any rule keeps it.
+#[derive(Args, Clone, Default)]
+pub struct RetentionPolicy {
+    #[clap(long, help = "keep backups newer than this, e.g. `30d`")]
+    pub keep_within: Option<String>,
+    #[clap(long, help = "keep the latest N backups", default_value = "0")]
+    pub keep_last: usize,
+    #[clap(
+        long,
+        help = "keep the latest backup of the latest N hours",
+        default_value = "0"
+    )]
+    pub keep_hourly: usize,
+    #[clap(
+        long,
+        help = "keep the latest backup of the latest N days",
+        default_value = "0"
+    )]
+    pub keep_daily: usize,
+    #[clap(
+        long,
+        help = "keep the latest backup of the latest N weeks",
+        default_value = "0"
+    )]
+    pub keep_weekly: usize,
+    #[clap(
+        long,
+        help = "keep the latest backup of the latest N months",
+        default_value = "0"
+    )]
+    pub keep_monthly: usize,
+    #[clap(
+        long,
+        help = "keep the latest backup of the latest N years",
+        default_value = "0"
+    )]
+    pub keep_yearly: usize,
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.keep_within.is_none()
+            && self.keep_last == 0
+            && self.keep_hourly == 0
+            && self.keep_daily == 0
+            && self.keep_weekly == 0
+            && self.keep_monthly == 0
+            && self.keep_yearly == 0
+    }
+
+    /// Returns the rules keeping each backup, backups not in the result expire.
+    pub fn apply(
+        &self,
+        backups: &[(DateTime<Local>, String)],
+        now: DateTime<Local>,
+    ) -> anyhow::Result<HashMap<String, Vec<&'static str>>> {
+        let mut sorted: Vec<_> = backups.iter().collect();
+        // newest first, so the latest backup of each bucket is kept
+        sorted.sort_by(|a, b| b.cmp(a));
+        let mut kept: HashMap<String, Vec<&'static str>> = HashMap::new();
+        if let Some(within) = &self.keep_within {
+            let dur = duration_str::parse(within)
+                .map_err(|why| anyhow!("cannot parse duration string: {}", why))?;
+            let t0 = now - dur;
+            for (t, name) in sorted.iter() {
+                if *t >= t0 {
+                    kept.entry(name.clone()).or_default().push("within");
+                }
+            }
+        }
+        for (_, name) in sorted.iter().take(self.keep_last) {
+            kept.entry(name.clone()).or_default().push("last");
+        }
+        let buckets: [(&'static str, usize, &str); 5] = [
+            ("hourly", self.keep_hourly, "%Y-%m-%d %H"),
+            ("daily", self.keep_daily, "%Y-%m-%d"),
+            ("weekly", self.keep_weekly, "%G-%V"),
+            ("monthly", self.keep_monthly, "%Y-%m"),
+            ("yearly", self.keep_yearly, "%Y"),
+        ];
+        for (rule, n, fmt) in buckets {
+            let mut last_bucket = None;
+            let mut count = 0;
+            for (t, name) in sorted.iter() {
+                if count >= n {
+                    break;
+                }
+                let bucket = t.format(fmt).to_string();
+                if last_bucket.as_ref() == Some(&bucket) {
+                    continue;
+                }
+                kept.entry(name.clone()).or_default().push(rule);
+                last_bucket = Some(bucket);
+                count += 1;
+            }
+        }
+        Ok(kept)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDateTime, TimeZone};
+    use std::collections::BTreeMap;
+
+    fn at(s: &str) -> DateTime<Local> {
+        let t = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+        Local.from_local_datetime(&t).unwrap()
+    }
+
+    /// Apply the policy to backups named after their times, and compare the kept backups and rules.
+    fn check(policy: RetentionPolicy, now: &str, backups: &[&str], expected: &[(&str, &[&str])]) {
+        let backups: Vec<_> = backups.iter().map(|t| (at(t), t.to_string())).collect();
+        let kept: BTreeMap<_, _> = policy
+            .apply(&backups, at(now))
+            .unwrap()
+            .into_iter()
+            .collect();
+        let expected: BTreeMap<_, _> = expected
+            .iter()
+            .map(|(name, rules)| (name.to_string(), rules.to_vec()))
+            .collect();
+        assert_eq!(kept, expected);
+    }
+
+    const NOW: &str = "2024-07-10 12:00";
+
+    #[test]
+    fn keep_within() {
+        let policy = RetentionPolicy {
+            keep_within: Some("2d".to_string()),
+            ..Default::default()
+        };
+        check(
+            policy,
+            NOW,
+            &[
+                "2024-07-07 12:00",
+                "2024-07-08 11:59",
+                "2024-07-08 12:00",
+                "2024-07-10 11:00",
+            ],
+            &[
+                ("2024-07-08 12:00", &["within"]),
+                ("2024-07-10 11:00", &["within"]),
+            ],
+        );
+    }
+
+    #[test]
+    fn keep_last() {
+        let policy = RetentionPolicy {
+            keep_last: 2,
+            ..Default::default()
+        };
+        check(
+            policy,
+            NOW,
+            &["2024-07-10 10:00", "2024-07-08 12:00", "2024-07-09 12:00"],
+            &[
+                ("2024-07-10 10:00", &["last"]),
+                ("2024-07-09 12:00", &["last"]),
+            ],
+        );
+    }
+
+    #[test]
+    fn keep_hourly() {
+        let policy = RetentionPolicy {
+            keep_hourly: 2,
+            ..Default::default()
+        };
+        check(
+            policy,
+            NOW,
+            &[
+                "2024-07-10 09:50",
+                "2024-07-10 10:05",
+                "2024-07-10 10:55",
+                "2024-07-10 11:10",
+            ],
+            &[
+                ("2024-07-10 11:10", &["hourly"]),
+                ("2024-07-10 10:55", &["hourly"]),
+            ],
+        );
+    }
+
+    #[test]
+    fn keep_daily() {
+        let policy = RetentionPolicy {
+            keep_daily: 2,
+            ..Default::default()
+        };
+        check(
+            policy,
+            NOW,
+            &[
+                "2024-07-08 23:00",
+                "2024-07-09 00:00",
+                "2024-07-09 23:59",
+                "2024-07-10 08:00",
+            ],
+            &[
+                ("2024-07-10 08:00", &["daily"]),
+                ("2024-07-09 23:59", &["daily"]),
+            ],
+        );
+    }
+
+    #[test]
+    fn keep_weekly() {
+        let policy = RetentionPolicy {
+            keep_weekly: 2,
+            ..Default::default()
+        };
+        // 2024-12-30 is a Monday in ISO week 2025-W01
+        check(
+            policy,
+            "2025-01-06 12:00",
+            &[
+                "2024-12-22 12:00",
+                "2024-12-29 12:00",
+                "2024-12-30 12:00",
+                "2025-01-05 12:00",
+            ],
+            &[
+                ("2025-01-05 12:00", &["weekly"]),
+                ("2024-12-29 12:00", &["weekly"]),
+            ],
+        );
+    }
+
+    #[test]
+    fn keep_monthly() {
+        let policy = RetentionPolicy {
+            keep_monthly: 2,
+            ..Default::default()
+        };
+        check(
+            policy,
+            NOW,
+            &[
+                "2024-05-31 12:00",
+                "2024-06-01 12:00",
+                "2024-06-30 12:00",
+                "2024-07-01 12:00",
+            ],
+            &[
+                ("2024-07-01 12:00", &["monthly"]),
+                ("2024-06-30 12:00", &["monthly"]),
+            ],
+        );
+    }
+
+    #[test]
+    fn keep_yearly() {
+        let policy = RetentionPolicy {
+            keep_yearly: 2,
+            ..Default::default()
+        };
+        check(
+            policy,
+            NOW,
+            &[
+                "2022-12-31 12:00",
+                "2023-01-01 12:00",
+                "2023-12-31 12:00",
+                "2024-07-01 12:00",
+            ],
+            &[
+                ("2024-07-01 12:00", &["yearly"]),
+                ("2023-12-31 12:00", &["yearly"]),
+            ],
+        );
+    }
+
+    #[test]
+    fn rules_combine() {
+        let policy = RetentionPolicy {
+            keep_last: 1,
+            keep_daily: 2,
+            keep_monthly: 2,
+            ..Default::default()
+        };
+        check(
+            policy,
+            NOW,
+            &[
+                "2024-06-15 12:00",
+                "2024-07-09 12:00",
+                "2024-07-10 08:00",
+                "2024-07-10 10:00",
+            ],
+            &[
+                ("2024-07-10 10:00", &["last", "daily", "monthly"]),
+                ("2024-07-09 12:00", &["daily"]),
+                ("2024-06-15 12:00", &["monthly"]),
+            ],
+        );
+    }
+
+    #[test]
+    fn same_time_in_bucket() {
+        let policy = RetentionPolicy {
+            keep_daily: 1,
+            ..Default::default()
+        };
+        let t = at("2024-07-10 08:00");
+        let backups = [(t, "a".to_string()), (t, "b".to_string())];
+        let kept = policy.apply(&backups, at(NOW)).unwrap();
+        // backups at the same time are ordered by name
+        assert_eq!(kept.keys().collect::<Vec<_>>(), ["b"]);
+    }
+
+    #[test]
+    fn empty_input() {
+        let policy = RetentionPolicy {
+            keep_within: Some("1d".to_string()),
+            keep_last: 1,
+            keep_hourly: 1,
+            keep_daily: 1,
+            keep_weekly: 1,
+            keep_monthly: 1,
+            keep_yearly: 1,
+        };
+        check(policy, NOW, &[], &[]);
+    }
+
+    #[test]
+    fn zero_policy_keeps_nothing() {
+        let policy = RetentionPolicy::default();
+        assert!(policy.is_empty());
+        check(policy, NOW, &["2024-07-09 12:00", "2024-07-10 11:00"], &[]);
+    }
+}
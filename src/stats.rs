@@ -0,0 +1,179 @@
+use crate::archive::list_kbi_files;
+use crate::java_objects::ZonedDateTime;
+use crate::kbi_verification::{list_files, load_kbi};
+use crate::repo_verification::list_repo_objects;
+use indicatif::HumanBytes;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::exit;
+
+#[derive(Serialize)]
+struct RepoStats {
+    objects: usize,
+    total_bytes: u64,
+    /// Objects referenced by at least one backup
+    referenced_objects: usize,
+    referenced_bytes: u64,
+    /// Referenced objects not in the repo, counted as 0 bytes
+    missing_objects: usize,
+    /// Sum of `total_size_bytes` of all backups
+    logical_bytes: u64,
+    /// `logical_bytes / referenced_bytes`
+    dedup_ratio: f64,
+    /// Ordered by backup time
+    backups: Vec<BackupUsage>,
+}
+
+#[derive(Serialize)]
+struct BackupUsage {
+    file_name: String,
+    time: ZonedDateTime,
+    files: i32,
+    logical_bytes: u64,
+    /// Bytes of objects referenced only by this backup, freed if it is removed
+    unique_bytes: u64,
+    /// Bytes of objects not referenced by earlier backups
+    new_bytes: u64,
+    /// Bytes of objects referenced by this and earlier backups
+    cumulative_bytes: u64,
+}
+
+/// Print size of the repo, cost of each backup and deduplication ratio.
+pub fn repo_stats(incr_repo: String, backups: String, json: bool) {
+    let kbi_files = match list_kbi_files(&backups) {
+        Ok(v) => v,
+        Err(why) => {
+            tracing::error!("failed to list backup files: {}", why);
+            exit(1);
+        }
+    };
+    let sizes: HashMap<String, u64> = match list_repo_objects(&incr_repo) {
+        Ok(v) => v
+            .into_iter()
+            .map(|obj| {
+                let size = fs::metadata(Path::new(&incr_repo).join(&obj))
+                    .map(|m| m.len())
+                    .unwrap_or(0);
+                (obj, size)
+            })
+            .collect(),
+        Err(why) => {
+            tracing::error!("failed to list incr files: {}", why);
+            exit(1);
+        }
+    };
+
+    // (usage, distinct objects of the backup)
+    let mut loaded = Vec::new();
+    for (_, file_name) in kbi_files {
+        let backup_info = match load_kbi(&Path::new(&backups).join(&file_name).to_string_lossy()) {
+            Ok(v) => v,
+            Err(why) => {
+                tracing::error!("error decoding kbi file {}: {}", &file_name, why);
+                continue;
+            }
+        };
+        let objects: HashSet<String> = list_files(&backup_info.object_collection2)
+            .values()
+            .map(|elem| elem.identifier.to_string())
+            .collect();
+        let usage = BackupUsage {
+            file_name,
+            time: backup_info.backup_time,
+            files: backup_info.total_files,
+            logical_bytes: backup_info.total_size_bytes.max(0) as u64,
+            unique_bytes: 0,
+            new_bytes: 0,
+            cumulative_bytes: 0,
+        };
+        loaded.push((usage, objects));
+    }
+    loaded.sort_by_key(|(usage, _)| usage.time.0);
+    let (mut usages, objects): (Vec<_>, Vec<_>) = loaded.into_iter().unzip();
+
+    let mut ref_count: HashMap<&String, usize> = HashMap::new();
+    for obj in objects.iter().flatten() {
+        *ref_count.entry(obj).or_default() += 1;
+    }
+    let size_of = |obj: &String| sizes.get(obj).copied().unwrap_or(0);
+    let mut seen = HashSet::new();
+    let mut cumulative_bytes = 0;
+    for (usage, objects) in usages.iter_mut().zip(objects.iter()) {
+        for obj in objects {
+            if ref_count[obj] == 1 {
+                usage.unique_bytes += size_of(obj);
+            }
+            if seen.insert(obj) {
+                usage.new_bytes += size_of(obj);
+            }
+        }
+        cumulative_bytes += usage.new_bytes;
+        usage.cumulative_bytes = cumulative_bytes;
+    }
+
+    let logical_bytes = usages.iter().map(|u| u.logical_bytes).sum();
+    let stats = RepoStats {
+        objects: sizes.len(),
+        total_bytes: sizes.values().sum(),
+        referenced_objects: ref_count.len(),
+        referenced_bytes: cumulative_bytes,
+        missing_objects: ref_count
+            .keys()
+            .filter(|obj| !sizes.contains_key(**obj))
+            .count(),
+        logical_bytes,
+        dedup_ratio: if cumulative_bytes == 0 {
+            0.0
+        } else {
+            logical_bytes as f64 / cumulative_bytes as f64
+        },
+        backups: usages,
+    };
+
+    if json {
+        if let Err(why) = serde_json::to_writer(io::stdout(), &stats)
+            && !why.is_io()
+        {
+            tracing::error!("error encoding JSON: {}", why);
+        }
+        println!();
+        return;
+    }
+    println!(
+        "objects: {} ({})",
+        stats.objects,
+        HumanBytes(stats.total_bytes)
+    );
+    println!(
+        "referenced objects: {} ({})",
+        stats.referenced_objects,
+        HumanBytes(stats.referenced_bytes)
+    );
+    if stats.missing_objects > 0 {
+        println!("missing objects: {}", stats.missing_objects);
+    }
+    println!(
+        "logical size of all backups: {}",
+        HumanBytes(stats.logical_bytes)
+    );
+    println!("dedup ratio: {:.2}", stats.dedup_ratio);
+    println!(
+        "{:<19} {:>8} {:>12} {:>12} {:>12} {:>12}  backup",
+        "time", "files", "logical", "unique", "new", "cumulative"
+    );
+    for u in stats.backups.iter() {
+        println!(
+            "{:<19} {:>8} {:>12} {:>12} {:>12} {:>12}  {}",
+            u.time.0.format("%Y-%m-%d %H:%M:%S").to_string(),
+            u.files,
+            HumanBytes(u.logical_bytes).to_string(),
+            HumanBytes(u.unique_bytes).to_string(),
+            HumanBytes(u.new_bytes).to_string(),
+            HumanBytes(u.cumulative_bytes).to_string(),
+            u.file_name
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use crate::archive::{list_kbi_files, parse_time_bound};
+use crate::kbi_verification::{list_files, load_kbi};
+use anyhow::anyhow;
+use chrono::{DateTime, Local};
+use indicatif::HumanBytes;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+#[derive(Default)]
+struct PathUsage {
+    /// Object size in the latest selected backup
+    size: u64,
+    objects: HashSet<String>,
+    /// Bytes of objects not referenced by any earlier backup
+    new_bytes: u64,
+}
+
+/// List the largest paths and the paths that changed most often in the selected backups.
+/// `from` and `to` are backup file names or times like `2024-03-05` or `2024-03-05_12-00-00`.
+pub fn top_paths(
+    incr_repo: String,
+    backups: String,
+    from: Option<String>,
+    to: Option<String>,
+    n: usize,
+) {
+    let kbi_files = match list_kbi_files(&backups) {
+        Ok(v) => v,
+        Err(why) => {
+            tracing::error!("failed to list backup files: {}", why);
+            exit(1);
+        }
+    };
+    let bound = |s: &Option<String>, end: bool| match s {
+        None => None,
+        Some(s) => match parse_bound(&kbi_files, s, end) {
+            Ok(v) => Some(v),
+            Err(why) => {
+                tracing::error!("{}", why);
+                exit(1);
+            }
+        },
+    };
+    let from = bound(&from, false);
+    let to = bound(&to, true);
+
+    // each object is stat-ed once, most are shared by many backups
+    let mut sizes: HashMap<String, u64> = HashMap::new();
+    let mut size_of = |obj: &str| match sizes.get(obj) {
+        Some(&size) => size,
+        None => {
+            let size = fs::metadata(Path::new(&incr_repo).join(obj))
+                .map(|m| m.len())
+                .unwrap_or(0);
+            sizes.insert(obj.to_string(), size);
+            size
+        }
+    };
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut usages: HashMap<PathBuf, PathUsage> = HashMap::new();
+    let mut selected = 0;
+    for (t, file_name) in kbi_files {
+        if to.is_some_and(|to| t.is_some_and(|t| t > to)) {
+            break;
+        }
+        let backup_info = match load_kbi(&Path::new(&backups).join(&file_name).to_string_lossy()) {
+            Ok(v) => v,
+            Err(why) => {
+                tracing::error!("error decoding kbi file {}: {}", &file_name, why);
+                continue;
+            }
+        };
+        let files = list_files(&backup_info.object_collection2);
+        if from.is_some_and(|from| t.is_none_or(|t| t < from)) {
+            // earlier backups only decide which objects are new
+            seen.extend(files.values().map(|elem| elem.identifier.to_string()));
+            continue;
+        }
+        selected += 1;
+        for (path, elem) in files {
+            let obj = elem.identifier.to_string();
+            let usage = usages.entry(path).or_default();
+            usage.size = size_of(&obj);
+            if seen.insert(obj.clone()) {
+                usage.new_bytes += usage.size;
+            }
+            usage.objects.insert(obj);
+        }
+    }
+    if selected == 0 {
+        tracing::error!("no backup in the given range");
+        exit(1);
+    }
+
+    let mut usages: Vec<_> = usages.into_iter().collect();
+    println!("largest files in {} backups:", selected);
+    usages.sort_by(|a, b| b.1.size.cmp(&a.1.size).then(a.0.cmp(&b.0)));
+    for (path, usage) in usages.iter().take(n) {
+        println!(
+            "{:>12}  {}",
+            HumanBytes(usage.size).to_string(),
+            path.display()
+        );
+    }
+    println!("most changed files (distinct versions):");
+    usages.sort_by(|a, b| {
+        b.1.objects
+            .len()
+            .cmp(&a.1.objects.len())
+            .then(a.0.cmp(&b.0))
+    });
+    for (path, usage) in usages.iter().take(n) {
+        println!("{:>12}  {}", usage.objects.len(), path.display());
+    }
+    println!("files with most new object bytes:");
+    usages.sort_by(|a, b| b.1.new_bytes.cmp(&a.1.new_bytes).then(a.0.cmp(&b.0)));
+    for (path, usage) in usages.iter().take(n) {
+        println!(
+            "{:>12}  {}",
+            HumanBytes(usage.new_bytes).to_string(),
+            path.display()
+        );
+    }
+}
+
+/// Time of a backup file name, or a time like `2024-03-05` or `2024-03-05_12-00-00`.
+fn parse_bound(
+    kbi_files: &[(Option<DateTime<Local>>, String)],
+    s: &str,
+    end: bool,
+) -> anyhow::Result<DateTime<Local>> {
+    if let Some((t, _)) = kbi_files.iter().find(|(_, name)| name == s) {
+        return t.ok_or_else(|| anyhow!("no time in backup file name: {}", s));
+    }
+    parse_time_bound(s, end)
+}
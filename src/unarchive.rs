@@ -0,0 +1,210 @@
+use crate::archive::{
+    parse_archive_time_from_filename, parse_backup_name_from_filename, parse_time_bound,
+};
+use crate::archive_journal::ArchiveJournal;
+use crate::atomic_file::write_atomic;
+use crate::hash_algorithm::HashAlgorithm;
+use crate::kbi_verification::collect_kbi_objects;
+use crate::move_file::move_file;
+use crate::repo_verification::hash_file;
+use anyhow::anyhow;
+use clap::Args;
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::path::Path;
+use std::process::exit;
+
+/// Archived backups to bring back
+#[derive(Args)]
+pub struct BackupSelection {
+    #[arg(help = "backup file names or backup names")]
+    pub names: Vec<String>,
+    #[clap(
+        long,
+        help = "first backup to bring back, as a time like `2024-03-05` or `2024-03-05_12-00-00`"
+    )]
+    pub from: Option<String>,
+    #[clap(
+        long,
+        help = "last backup to bring back, as a time like `2024-03-05` or `2024-03-05_12-00-00`"
+    )]
+    pub to: Option<String>,
+}
+
+/// Move backups back from the archive, together with all objects they reference.
+/// Objects go first, so a backup is never in place before its objects.
+pub fn unarchive_backups(
+    incr_repo: String,
+    backups: String,
+    archive_incr_repo: String,
+    archive_backups: String,
+    selection: BackupSelection,
+    dry_run: bool,
+) {
+    if let Err(why) = ArchiveJournal::ensure_none_pending(&incr_repo) {
+        tracing::error!("{}", why);
+        exit(1);
+    }
+    if selection.names.is_empty() && selection.from.is_none() && selection.to.is_none() {
+        tracing::error!("no backup selected, give backup names or --from/--to");
+        exit(1);
+    }
+    let bound = |s: &Option<String>, end: bool| match s.as_deref().map(|s| parse_time_bound(s, end))
+    {
+        Some(Ok(t)) => Some(t),
+        Some(Err(why)) => {
+            tracing::error!("{}", why);
+            exit(1);
+        }
+        None => None,
+    };
+    let from = bound(&selection.from, false);
+    let to = bound(&selection.to, true);
+
+    let archived = match list_backup_files(&archive_backups) {
+        Ok(v) => v,
+        Err(why) => {
+            tracing::error!("failed to list archived backup files: {}", why);
+            exit(1);
+        }
+    };
+    let (selected, remaining): (Vec<String>, Vec<String>) =
+        archived.into_iter().partition(|file_name| {
+            let by_name = selection.names.iter().any(|n| {
+                n == file_name || parse_backup_name_from_filename(file_name).is_ok_and(|b| b == n)
+            });
+            let by_time = (from.is_some() || to.is_some())
+                && parse_archive_time_from_filename(file_name).is_ok_and(|t| {
+                    from.is_none_or(|from| t >= from) && to.is_none_or(|to| t <= to)
+                });
+            by_name || by_time
+        });
+    if selected.is_empty() {
+        tracing::error!("no archived backup matches");
+        exit(1);
+    }
+
+    let collect = |file_names: &[String]| -> HashSet<String> {
+        let mut objects = HashSet::new();
+        for file_name in file_names.iter().filter(|f| f.ends_with(".kbi")) {
+            let p = Path::new(&archive_backups).join(file_name);
+            match collect_kbi_objects(p.to_string_lossy().into_owned(), archive_incr_repo.clone()) {
+                Ok(v) => objects.extend(v.into_iter().map(|(_, obj)| obj)),
+                Err(why) => {
+                    tracing::error!("error decoding kbi file {}: {}", file_name, why);
+                    exit(1);
+                }
+            }
+        }
+        objects
+    };
+    let wanted: BTreeSet<String> = collect(&selected).into_iter().collect();
+    // objects still used by other archived backups are copied instead of moved
+    let still_used = collect(&remaining);
+
+    let mut missing = Vec::new();
+    let mut failed = 0usize;
+    for obj in wanted.iter() {
+        let dst = Path::new(&incr_repo).join(obj);
+        if dst.is_file() {
+            tracing::debug!("already in repo: {}", obj);
+            continue;
+        }
+        let src = Path::new(&archive_incr_repo).join(obj);
+        if !src.is_file() {
+            missing.push(obj);
+            continue;
+        }
+        if dry_run {
+            tracing::info!("unarchived: {}", obj);
+            continue;
+        }
+        match unarchive_object(&src, &dst, obj, still_used.contains(obj)) {
+            Ok(()) => tracing::info!("unarchived: {}", obj),
+            Err(why) => {
+                tracing::error!("error unarchiving object {}: {}", obj, why);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        tracing::error!(
+            "failed to unarchive {} objects, backups are not moved",
+            failed
+        );
+        exit(1);
+    }
+
+    for file_name in selected.iter() {
+        if dry_run {
+            tracing::info!("unarchived: {}", file_name);
+            continue;
+        }
+        match move_file(
+            &Path::new(&archive_backups).join(file_name),
+            &Path::new(&backups).join(file_name),
+        ) {
+            Ok(()) => tracing::info!("unarchived: {}", file_name),
+            Err(why) => {
+                tracing::error!("error moving backup file {}: {}", file_name, why);
+                failed += 1;
+            }
+        }
+    }
+    for obj in missing.iter() {
+        println!("missing in archive: {}", obj);
+    }
+    if !missing.is_empty() {
+        tracing::error!(
+            "{} objects are missing in the archive too, unarchived backups are damaged",
+            missing.len()
+        );
+    }
+    if failed > 0 || !missing.is_empty() {
+        exit(1);
+    }
+}
+
+/// Verify the archived object, then move it into the repo, or copy it if `keep` is set.
+fn unarchive_object(src: &Path, dst: &Path, obj: &str, keep: bool) -> anyhow::Result<()> {
+    let (alg, expected_hash) = HashAlgorithm::from_object_name(obj)?;
+    let actual_hash = if keep {
+        let dir = dst.parent().unwrap_or(Path::new("."));
+        write_atomic(fs::File::open(src)?, dir, alg, |_, hash| {
+            Ok((hash == expected_hash).then(|| dst.to_path_buf()))
+        })?
+        .0
+    } else {
+        let hash = hash_file(alg, src.to_path_buf())?;
+        if hash == expected_hash {
+            move_file(src, dst)?;
+        }
+        hash
+    };
+    if actual_hash != expected_hash {
+        return Err(anyhow!(
+            "file hash mismatch, expected: {}, actual: {}",
+            expected_hash,
+            actual_hash
+        ));
+    }
+    Ok(())
+}
+
+/// File names of .kbi and .zip backups in the folder
+fn list_backup_files(dir: &str) -> anyhow::Result<Vec<String>> {
+    let mut result = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        match entry.file_name().into_string() {
+            Ok(v) if parse_archive_time_from_filename(&v).is_ok() => result.push(v),
+            Ok(_) => continue,
+            Err(s) => return Err(anyhow!("cannot decode OsString {:?}", s)),
+        }
+    }
+    result.sort();
+    Ok(result)
+}
@@ -0,0 +1,89 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default file name of the cache inside the incremental repo
+pub const CACHE_FILE_NAME: &str = ".kbackup-utils-verify-cache.json";
+
+/// Objects verified in previous runs, keyed by object file name.
+#[derive(Serialize, Deserialize, Default)]
+pub struct VerificationCache {
+    objects: BTreeMap<String, CacheEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct CacheEntry {
+    pub size: u64,
+    /// Modification time in nanoseconds since UNIX epoch
+    pub mtime: u64,
+    /// Verification time in seconds since UNIX epoch
+    pub verified: u64,
+}
+
+impl CacheEntry {
+    pub fn from_metadata(m: &fs::Metadata, verified: SystemTime) -> Self {
+        CacheEntry {
+            size: m.len(),
+            mtime: m.modified().map(unix_nanos).unwrap_or(0),
+            verified: unix_nanos(verified) / 1_000_000_000,
+        }
+    }
+}
+
+fn unix_nanos(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+impl VerificationCache {
+    /// Load the cache, a missing file gives an empty cache.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        match File::open(path) {
+            Ok(f) => Ok(serde_json::from_reader(BufReader::new(f))?),
+            Err(why) if why.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(why) => Err(why.into()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut w, self)?;
+        w.flush()?;
+        w.into_inner()?.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Whether the object was verified after `not_before` and its metadata is unchanged.
+    pub fn is_fresh(&self, object: &str, current: &CacheEntry, not_before: SystemTime) -> bool {
+        match self.objects.get(object) {
+            Some(e) => {
+                e.size == current.size
+                    && e.mtime == current.mtime
+                    && Duration::from_secs(e.verified)
+                        >= not_before.duration_since(UNIX_EPOCH).unwrap_or_default()
+            }
+            None => false,
+        }
+    }
+
+    pub fn insert(&mut self, object: String, entry: CacheEntry) {
+        self.objects.insert(object, entry);
+    }
+
+    pub fn remove(&mut self, object: &str) {
+        self.objects.remove(object);
+    }
+
+    /// Drop entries of objects no longer in the repo.
+    pub fn retain<F: FnMut(&str) -> bool>(&mut self, mut f: F) {
+        self.objects.retain(|k, _| f(k));
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use zip::ZipArchive;
+
+/// Verify CRC-32 and sizes of all entries in full backup .zip files.
+/// Directories in `paths` are scanned for .zip files.
+pub fn verify_zips(paths: Vec<String>, mut threads: usize) {
+    let mut zip_files = Vec::new();
+    for path in paths {
+        match list_zip_files(Path::new(&path)) {
+            Ok(v) => zip_files.extend(v),
+            Err(why) => {
+                tracing::error!("error listing {}: {}", &path, why);
+                exit(1);
+            }
+        }
+    }
+
+    let failures = AtomicUsize::new(0);
+    let unopenable = Mutex::new(HashSet::new());
+    let (send, recv) = crossbeam::channel::bounded(1024);
+    if threads == 0 {
+        threads = num_cpus::get();
+    }
+    crossbeam::thread::scope(|s| {
+        let failures = &failures;
+        s.spawn(move |_| {
+            for zip_path in zip_files {
+                // the central directory is read here first, so truncated archives are detected early
+                let n = match File::open(&zip_path)
+                    .map_err(zip::result::ZipError::from)
+                    .and_then(ZipArchive::new)
+                {
+                    Ok(archive) => archive.len(),
+                    Err(why) => {
+                        println!("corrupted zip: {}: {}", zip_path.display(), why);
+                        failures.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                };
+                for i in 0..n {
+                    send.send((zip_path.clone(), i))
+                        .expect("error sending entry");
+                }
+            }
+        });
+        for _ in 0..threads {
+            let recv = recv.clone();
+            let unopenable = &unopenable;
+            s.spawn(move |_| {
+                // entries are queued one zip at a time, so each worker only keeps the current zip open;
+                // `None` if it cannot be opened, its remaining entries are skipped
+                let mut current: Option<(PathBuf, Option<ZipArchive<File>>)> = None;
+                recv.iter().for_each(|(zip_path, i)| {
+                    if current.as_ref().is_none_or(|(p, _)| *p != zip_path) {
+                        let archive = match File::open(&zip_path)
+                            .map_err(zip::result::ZipError::from)
+                            .and_then(ZipArchive::new)
+                        {
+                            Ok(v) => Some(v),
+                            Err(why) => {
+                                // other workers may fail on the same zip, count it once
+                                if unopenable.lock().unwrap().insert(zip_path.clone()) {
+                                    println!("corrupted zip: {}: {}", zip_path.display(), why);
+                                    failures.fetch_add(1, Ordering::Relaxed);
+                                }
+                                None
+                            }
+                        };
+                        current = Some((zip_path.clone(), archive));
+                    }
+                    let Some((_, Some(archive))) = current.as_mut() else {
+                        return;
+                    };
+                    if let Err(why) = verify_entry(archive, i) {
+                        println!("corrupted entry: {}: {}", zip_path.display(), why);
+                        failures.fetch_add(1, Ordering::Relaxed);
+                    }
+                });
+            });
+        }
+    })
+    .unwrap();
+
+    let failures = failures.into_inner();
+    if failures > 0 {
+        tracing::error!("{} zip files or entries are corrupted", failures);
+        exit(1);
+    }
+}
+
+fn verify_entry(archive: &mut ZipArchive<File>, i: usize) -> anyhow::Result<()> {
+    let mut entry = archive
+        .by_index(i)
+        .map_err(|why| anyhow::anyhow!("entry #{}: {}", i, why))?;
+    let name = entry
+        .name()
+        .map(|s| s.into_owned())
+        .unwrap_or_else(|_| format!("entry #{}", i));
+    // CRC-32 in the central directory is checked by the reader when reaching the end of entry
+    let n = io::copy(&mut entry, &mut io::sink())
+        .map_err(|why| anyhow::anyhow!("{}: {}", name, why))?;
+    if n != entry.size() {
+        return Err(anyhow::anyhow!(
+            "{}: size mismatch, expected: {}, actual: {}",
+            name,
+            entry.size(),
+            n
+        ));
+    }
+    tracing::debug!("checksum OK: {}", name);
+    Ok(())
+}
+
+pub fn list_zip_files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut result = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() && entry.file_name().to_string_lossy().ends_with(".zip") {
+            result.push(entry.path());
+        }
+    }
+    result.sort();
+    Ok(result)
+}
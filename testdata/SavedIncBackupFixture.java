@@ -0,0 +1,105 @@
+// Writes saved_inc_backup.kbi, a .kbi file with the layout of KBackup-Fabric's SavedIncBackupV1.
+// Run with: java SavedIncBackupFixture.java saved_inc_backup.kbi
+import java.io.FileOutputStream;
+import java.io.ObjectOutputStream;
+import java.io.Serializable;
+import java.time.ZoneId;
+import java.time.ZonedDateTime;
+import java.util.HashMap;
+import java.util.Map;
+
+public class SavedIncBackupFixture {
+    abstract static class SingleHashIdentifier implements Serializable {
+        private static final long serialVersionUID = 1L;
+        private final byte[] hash;
+        private final String type;
+
+        SingleHashIdentifier(byte[] hash, String type) {
+            this.hash = hash;
+            this.type = type;
+        }
+    }
+
+    static class Sha256Identifier extends SingleHashIdentifier {
+        private static final long serialVersionUID = 2L;
+
+        Sha256Identifier(byte[] hash) {
+            super(hash, "S2");
+        }
+    }
+
+    static class ObjectElement implements Serializable {
+        private static final long serialVersionUID = 3L;
+        private final String name;
+        private final SingleHashIdentifier identifier;
+
+        ObjectElement(String name, SingleHashIdentifier identifier) {
+            this.name = name;
+            this.identifier = identifier;
+        }
+    }
+
+    static class ObjectCollection2 implements Serializable {
+        private static final long serialVersionUID = 4L;
+        private final String name;
+        private final Map<String, ObjectElement> elements = new HashMap<>();
+        private final Map<String, ObjectCollection2> subCollections = new HashMap<>();
+
+        ObjectCollection2(String name) {
+            this.name = name;
+        }
+
+        ObjectCollection2 file(String name, int... hash) {
+            byte[] bytes = new byte[hash.length];
+            for (int i = 0; i < hash.length; i++) {
+                bytes[i] = (byte) hash[i];
+            }
+            elements.put(name, new ObjectElement(name, new Sha256Identifier(bytes)));
+            return this;
+        }
+
+        ObjectCollection2 dir(ObjectCollection2 sub) {
+            subCollections.put(sub.name, sub);
+            return this;
+        }
+    }
+
+    static class SavedIncBackupV1 implements Serializable {
+        private static final long serialVersionUID = 5L;
+        private final ObjectCollection2 objectCollection2;
+        private final String backupName;
+        private final ZonedDateTime backupTime;
+        private final long totalSizeBytes;
+        private final long increasedSizeBytes;
+        private final int filesAdded;
+        private final int totalFiles;
+
+        SavedIncBackupV1(ObjectCollection2 objectCollection2, String backupName, ZonedDateTime backupTime) {
+            this.objectCollection2 = objectCollection2;
+            this.backupName = backupName;
+            this.backupTime = backupTime;
+            this.totalSizeBytes = 1L << 40;
+            this.increasedSizeBytes = 1234;
+            this.filesAdded = 2;
+            this.totalFiles = 7;
+        }
+    }
+
+    public static void main(String[] args) throws Exception {
+        ObjectCollection2 region = new ObjectCollection2("region")
+                .file("r.0.0.mca", 1, 2, 3)
+                .file("r.0.-1.mca", 4)
+                .file("r.-1.0.mca", 5, 6)
+                .file("r.-1.-1.mca", 0xff);
+        ObjectCollection2 world = new ObjectCollection2("world")
+                .file("level.dat", 0xde, 0xad, 0xbe, 0xef)
+                .file("level.dat_old", 0xde, 0xad, 0xbe, 0xef)
+                .file("session.lock")
+                .dir(region)
+                .dir(new ObjectCollection2("data"));
+        ZonedDateTime time = ZonedDateTime.of(2024, 3, 5, 12, 30, 15, 0, ZoneId.of("Asia/Shanghai"));
+        try (ObjectOutputStream out = new ObjectOutputStream(new FileOutputStream(args[0]))) {
+            out.writeObject(new SavedIncBackupV1(world, "测试 backup", time));
+        }
+    }
+}